@@ -0,0 +1,4 @@
+// takes precedence over lib/vendor/units.libsonnet
+(import '../vendor/units.libsonnet') {
+  percent(ratio):: std.round(ratio * 1000) / 10,
+}
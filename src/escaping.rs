@@ -0,0 +1,65 @@
+/// Rules for escaping metric names, which differ per output format.
+#[derive(Clone, Copy, Debug)]
+pub enum NameEscaping {
+    /// Characters outside of `[a-zA-Z0-9_:]` are replaced by `_`, a leading
+    /// digit is prefixed with `_`.
+    Prometheus,
+    /// Colons separate the nodes of the dotted name, characters outside of
+    /// `[a-zA-Z0-9_.-]` are replaced by `_`.
+    Graphite,
+}
+
+impl NameEscaping {
+    pub fn escape(&self, name: &str) -> String {
+        let mut escaped = String::with_capacity(name.len());
+        match self {
+            NameEscaping::Prometheus => {
+                if name.starts_with(|c: char| c.is_ascii_digit()) {
+                    escaped.push('_');
+                }
+                for c in name.chars() {
+                    match c {
+                        'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | ':' => escaped.push(c),
+                        _ => escaped.push('_'),
+                    }
+                }
+            }
+            NameEscaping::Graphite => {
+                for c in name.chars() {
+                    match c {
+                        'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '.' | '-' => escaped.push(c),
+                        ':' => escaped.push('.'),
+                        _ => escaped.push('_'),
+                    }
+                }
+            }
+        }
+        escaped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_prometheus() {
+        assert_eq!(
+            NameEscaping::Prometheus.escape("cache.hit-ratio:5m"),
+            "cache_hit_ratio:5m"
+        );
+        assert_eq!(NameEscaping::Prometheus.escape("5xx"), "_5xx");
+    }
+
+    #[test]
+    fn escape_graphite() {
+        assert_eq!(
+            NameEscaping::Graphite.escape("cache.hit-ratio:5m"),
+            "cache.hit-ratio.5m"
+        );
+        assert_eq!(
+            NameEscaping::Graphite.escape("disk used (%) ✓"),
+            "disk_used______"
+        );
+    }
+}
@@ -0,0 +1,257 @@
+use std::fmt::Write;
+
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
+
+use crate::escaping::NameEscaping;
+use crate::openmetrics;
+
+pub const FORMAT_TYPE: &str = "text/plain; charset=utf-8";
+
+/// Tag values must not contain `;`, `~` or whitespace.
+fn escape_tag_value(v: &str) -> String {
+    v.chars()
+        .map(|c| match c {
+            ';' | '~' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Formats the upper bound of a histogram bucket like Prometheus does.
+fn fmt_upper_bound(upper_bound: f64) -> String {
+    if upper_bound == f64::INFINITY {
+        "+Inf".to_string()
+    } else {
+        upper_bound.to_string()
+    }
+}
+
+fn write_sample(
+    out: &mut String,
+    name: &str,
+    suffix: &str,
+    labels: &[LabelPair],
+    extra_label: Option<(&str, String)>,
+    value: f64,
+    timestamp: i64,
+) {
+    out.push_str(&NameEscaping::Graphite.escape(name));
+    out.push_str(suffix);
+    for l in labels {
+        write!(
+            out,
+            ";{}={}",
+            NameEscaping::Graphite.escape(l.get_name()),
+            escape_tag_value(l.get_value())
+        )
+        .unwrap();
+    }
+    if let Some((name, value)) = extra_label {
+        write!(out, ";{}={}", name, escape_tag_value(&value)).unwrap();
+    }
+    writeln!(out, " {} {}", value, timestamp).unwrap();
+}
+
+/// Encodes the metric families in the Graphite plaintext protocol, labels are
+/// sent as tags.
+pub fn encode(metric_families: &[MetricFamily], timestamp: i64) -> String {
+    let mut out = String::new();
+
+    for mf in metric_families {
+        let name = mf.get_name();
+        if openmetrics::is_exemplar_family(name) {
+            continue;
+        }
+
+        for m in mf.get_metric() {
+            let labels = m.get_label();
+            match mf.get_field_type() {
+                MetricType::COUNTER => write_sample(
+                    &mut out,
+                    name,
+                    "",
+                    labels,
+                    None,
+                    m.get_counter().get_value(),
+                    timestamp,
+                ),
+                MetricType::GAUGE => write_sample(
+                    &mut out,
+                    name,
+                    "",
+                    labels,
+                    None,
+                    m.get_gauge().get_value(),
+                    timestamp,
+                ),
+                MetricType::UNTYPED => write_sample(
+                    &mut out,
+                    name,
+                    "",
+                    labels,
+                    None,
+                    m.get_untyped().get_value(),
+                    timestamp,
+                ),
+                MetricType::HISTOGRAM => {
+                    let h = m.get_histogram();
+                    let mut inf_seen = false;
+                    for b in h.get_bucket() {
+                        inf_seen |= b.get_upper_bound() == f64::INFINITY;
+                        write_sample(
+                            &mut out,
+                            name,
+                            "_bucket",
+                            labels,
+                            Some(("le", fmt_upper_bound(b.get_upper_bound()))),
+                            b.get_cumulative_count() as f64,
+                            timestamp,
+                        );
+                    }
+                    if !inf_seen {
+                        write_sample(
+                            &mut out,
+                            name,
+                            "_bucket",
+                            labels,
+                            Some(("le", fmt_upper_bound(f64::INFINITY))),
+                            h.get_sample_count() as f64,
+                            timestamp,
+                        );
+                    }
+                    write_sample(
+                        &mut out,
+                        name,
+                        "_count",
+                        labels,
+                        None,
+                        h.get_sample_count() as f64,
+                        timestamp,
+                    );
+                    write_sample(
+                        &mut out,
+                        name,
+                        "_sum",
+                        labels,
+                        None,
+                        h.get_sample_sum(),
+                        timestamp,
+                    );
+                }
+                MetricType::SUMMARY => {
+                    let s = m.get_summary();
+                    for q in s.get_quantile() {
+                        write_sample(
+                            &mut out,
+                            name,
+                            "",
+                            labels,
+                            Some(("quantile", q.get_quantile().to_string())),
+                            q.get_value(),
+                            timestamp,
+                        );
+                    }
+                    write_sample(
+                        &mut out,
+                        name,
+                        "_count",
+                        labels,
+                        None,
+                        s.get_sample_count() as f64,
+                        timestamp,
+                    );
+                    write_sample(
+                        &mut out,
+                        name,
+                        "_sum",
+                        labels,
+                        None,
+                        s.get_sample_sum(),
+                        timestamp,
+                    );
+                }
+            }
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::proto::{Bucket, Histogram, Metric};
+
+    fn label(name: &str, value: &str) -> LabelPair {
+        let mut label = LabelPair::default();
+        label.set_name(name.to_string());
+        label.set_value(value.to_string());
+        label
+    }
+
+    #[test]
+    fn encode_tags() {
+        let mut gauge = Metric::default();
+        gauge.set_label(vec![label("mount point", "/var/lib; data")].into());
+        gauge.mut_gauge().set_value(0.5);
+        let mut used = MetricFamily::default();
+        used.set_name("disk:used_ratio".to_string());
+        used.set_field_type(MetricType::GAUGE);
+        used.mut_metric().push(gauge);
+
+        let mut bucket = Bucket::default();
+        bucket.set_upper_bound(0.1);
+        bucket.set_cumulative_count(2);
+        let mut histogram = Histogram::default();
+        histogram.mut_bucket().push(bucket);
+        histogram.set_sample_count(3);
+        histogram.set_sample_sum(0.5);
+        let mut metric = Metric::default();
+        metric.set_histogram(histogram);
+        let mut latency = MetricFamily::default();
+        latency.set_name("latency_seconds".to_string());
+        latency.set_field_type(MetricType::HISTOGRAM);
+        latency.mut_metric().push(metric);
+
+        let mut exemplars = MetricFamily::default();
+        exemplars.set_name(openmetrics::exemplar_name("latency_seconds"));
+
+        assert_eq!(
+            encode(&[used, latency, exemplars], 1600000000),
+            "disk.used_ratio;mount_point=/var/lib__data 0.5 1600000000\n\
+             latency_seconds_bucket;le=0.1 2 1600000000\n\
+             latency_seconds_bucket;le=+Inf 3 1600000000\n\
+             latency_seconds_count 3 1600000000\n\
+             latency_seconds_sum 0.5 1600000000\n"
+        );
+    }
+
+    #[test]
+    fn encode_histogram_inf_bucket() {
+        let mut histogram = Histogram::default();
+        for (upper_bound, count) in &[(1.0, 1), (f64::INFINITY, 2)] {
+            let mut bucket = Bucket::default();
+            bucket.set_upper_bound(*upper_bound);
+            bucket.set_cumulative_count(*count);
+            histogram.mut_bucket().push(bucket);
+        }
+        histogram.set_sample_count(2);
+        histogram.set_sample_sum(3.0);
+        let mut metric = Metric::default();
+        metric.set_histogram(histogram);
+        let mut latency = MetricFamily::default();
+        latency.set_name("latency_seconds".to_string());
+        latency.set_field_type(MetricType::HISTOGRAM);
+        latency.mut_metric().push(metric);
+
+        // a stored +Inf bucket isn't repeated
+        assert_eq!(
+            encode(&[latency], 1600000000),
+            "latency_seconds_bucket;le=1 1 1600000000\n\
+             latency_seconds_bucket;le=+Inf 2 1600000000\n\
+             latency_seconds_count 2 1600000000\n\
+             latency_seconds_sum 3 1600000000\n"
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use bytes::Buf;
 use clap::Clap;
 use env_logger::{Builder, Env};
+use flate2::{write::GzEncoder, Compression};
 use log::{debug, error, info};
 use pretty_assertions::Comparison;
 use serde::{Deserialize, Serialize};
@@ -9,43 +10,125 @@ use std::error::Error;
 use std::fs::File;
 use std::io::BufReader;
 use std::net::SocketAddr;
-use std::{any::Any, io::prelude::*, path::PathBuf, rc::Rc};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{
+    any::Any,
+    io::prelude::*,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 use warp::{
-    http::header::HeaderValue, http::header::CONTENT_TYPE, http::Response, Filter, Rejection, Reply,
+    http::header::HeaderValue, http::header::ACCEPT, http::header::ACCEPT_ENCODING,
+    http::header::CACHE_CONTROL, http::header::CONTENT_ENCODING, http::header::CONTENT_LENGTH,
+    http::header::CONTENT_TYPE, http::header::ETAG, http::header::LOCATION, http::header::VARY,
+    http::HeaderMap, http::Response, Filter, Rejection, Reply,
 };
 
-use prometheus::{labels, opts, register_counter, register_gauge, register_histogram_vec};
-use prometheus::{Counter, Encoder, Gauge, HistogramVec, TextEncoder};
+use prometheus::core::Collector;
+use prometheus::proto::MetricFamily;
+use prometheus::{histogram_opts, labels, opts};
+use prometheus::{Counter, CounterVec, Encoder, Gauge, HistogramVec, TextEncoder};
 
 use jrsonnet_evaluator::{
-    native::NativeCallback, throw, EvaluationState, FileImportResolver, ImportResolver, Val,
+    error::LocError, throw, EvaluationState, FileImportResolver, ImportResolver, Val,
 };
 use jrsonnet_interner::IStr;
-use jrsonnet_parser::{Param, ParamsDesc};
+use jrsonnet_parser::{LocExpr, ParserSettings};
+
+use sha2::{Digest, Sha256};
 
 use lazy_static::lazy_static;
 
-use regex::Regex;
+#[cfg(feature = "count-allocations")]
+mod allocations;
+mod escaping;
+mod graphite;
+mod natives;
+mod openmetrics;
+mod transforms;
+
+use escaping::NameEscaping;
+use natives::Clock;
 
 lazy_static! {
-    static ref HTTP_COUNTER: Counter = register_counter!(opts!(
-        "example_http_requests_total",
+    static ref HTTP_COUNTER: Counter = Counter::with_opts(opts!(
+        "http_requests_total",
         "Number of HTTP requests made.",
         labels! {"handler" => "all",}
     ))
     .unwrap();
-    static ref HTTP_BODY_GAUGE: Gauge = register_gauge!(opts!(
-        "example_http_response_size_bytes",
+    static ref HTTP_BODY_GAUGE: Gauge = Gauge::with_opts(opts!(
+        "http_response_size_bytes",
         "The HTTP response sizes in bytes.",
         labels! {"handler" => "all",}
     ))
     .unwrap();
-    static ref HTTP_REQ_HISTOGRAM: HistogramVec = register_histogram_vec!(
-        "example_http_request_duration_seconds",
-        "The HTTP request latencies in seconds.",
+    static ref HTTP_REQ_HISTOGRAM: HistogramVec = HistogramVec::new(
+        histogram_opts!(
+            "http_request_duration_seconds",
+            "The HTTP request latencies in seconds."
+        ),
         &["handler"]
     )
     .unwrap();
+    static ref JSONNET_PARSES: Counter = Counter::with_opts(opts!(
+        "parses_total",
+        "Number of times module jsonnet has been parsed."
+    ))
+    .unwrap();
+    static ref EVALUATIONS: CounterVec = CounterVec::new(
+        opts!("evaluations_total", "Number of evaluations of a module."),
+        &["module"]
+    )
+    .unwrap();
+}
+
+/// Builds the registry of the exporter's own metrics, which prefixes their
+/// names with the namespace.
+fn self_metrics_registry(namespace: &str) -> prometheus::Registry {
+    let prefix = Some(namespace.to_string()).filter(|namespace| !namespace.is_empty());
+    let registry = prometheus::Registry::new_custom(prefix, None).unwrap();
+    registry.register(Box::new(HTTP_COUNTER.clone())).unwrap();
+    registry
+        .register(Box::new(HTTP_BODY_GAUGE.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(HTTP_REQ_HISTOGRAM.clone()))
+        .unwrap();
+    registry.register(Box::new(JSONNET_PARSES.clone())).unwrap();
+    registry.register(Box::new(EVALUATIONS.clone())).unwrap();
+    registry
+}
+
+/// Parsed jsonnet keyed by path and content hash, shared by all evaluations
+/// so unchanged modules are not parsed again. Syntax trees are reference
+/// counted within a thread, so they are kept serialized.
+#[derive(Default)]
+struct ParseCache(Mutex<HashMap<String, Arc<Vec<u8>>>>);
+
+impl ParseCache {
+    fn parse(&self, path: &Rc<PathBuf>, code: &str) -> Result<LocExpr> {
+        let key = format!("{}:{:x}", path.display(), Sha256::digest(code.as_bytes()));
+        let cached = self.0.lock().unwrap().get(&key).cloned();
+        if let Some(serialized) = cached {
+            return Ok(bincode::deserialize(&serialized)?);
+        }
+
+        JSONNET_PARSES.inc();
+        let parsed = jrsonnet_parser::parse(
+            code,
+            &ParserSettings {
+                loc_data: true,
+                file_name: path.clone(),
+            },
+        )
+        .map_err(|e| format!("cannot parse {}: {:?}", path.display(), e))?;
+
+        let serialized = Arc::new(bincode::serialize(&parsed)?);
+        self.0.lock().unwrap().insert(key, serialized);
+        Ok(parsed)
+    }
 }
 
 #[derive(Clap)]
@@ -59,38 +142,414 @@ struct Opts {
     #[clap(long = "config-file", default_value = "config.yaml")]
     config_file: String,
 
+    /// Base directory for `jsonnet_path`s and relative imports of modules,
+    /// defaults to the directory of the config file.
+    #[clap(long = "base-dir")]
+    base_dir: Option<PathBuf>,
+
     /// Library search dirs.
     /// Any not found `imported` file will be searched in these.
     /// This can also be specified via `JSONNET_PATH` variable,
     /// which should contain a colon-separated (semicolon-separated on Windows) list of directories.
     #[clap(long, short = 'J')]
-    _jpath: Vec<PathBuf>,
+    jpath: Vec<PathBuf>,
+
+    /// Fix the time returned by the `now()` native to this unix timestamp.
+    #[clap(long)]
+    clock: Option<f64>,
+
+    /// Number of most recent probe results, per module and target, to include
+    /// in `/metrics`. Background modules are part of it anyway and not
+    /// included again.
+    #[clap(long = "metrics-include-probes", default_value = "0")]
+    metrics_include_probes: usize,
+
+    /// Enable debug endpoints, like `/debug/last-error` and `/tests`, and
+    /// annotate metrics on `/metrics` with the module they originate from.
+    #[clap(long)]
+    debug: bool,
+
+    /// Namespace prefixed to the names of the exporter's own metrics.
+    #[clap(long = "metrics-namespace", default_value = "jsonnet_exporter")]
+    metrics_namespace: String,
+
+    /// Expose the checksum of the config file on `/metrics`, to detect
+    /// replicas running with different configs.
+    #[clap(long = "config-checksum-metric")]
+    config_checksum_metric: bool,
+
+    /// Stream the body of `/metrics`, encoding one metric family at a time,
+    /// instead of buffering it. The families themselves are gathered upfront.
+    /// Streamed responses carry no `ETag` and are not compressed.
+    #[clap(long = "stream-metrics")]
+    stream_metrics: bool,
+
+    /// Serve the metrics of every module on `/metrics/{module}`, from its
+    /// background scrape or by probing its configured targets. Exposition
+    /// groups take precedence over modules of the same name.
+    #[clap(long = "metrics-per-module")]
+    metrics_per_module: bool,
+
+    #[clap(subcommand)]
+    subcmd: Option<SubCommand>,
+}
+
+#[derive(Clap)]
+enum SubCommand {
+    /// Evaluates a module repeatedly against a sample input and reports its
+    /// latency, and its allocations when built with the `count-allocations`
+    /// feature.
+    Bench(Bench),
+
+    /// Probes a module once, prints the exposition to stdout and exits.
+    RunOnce(RunOnce),
+
+    /// Runs the tests of all modules and reports their results.
+    Test(Test),
+
+    /// Evaluates a module against a body read from a file and prints the
+    /// exposition to stdout.
+    Eval(Eval),
+}
+
+#[derive(Clap)]
+struct Eval {
+    /// The module to evaluate.
+    #[clap(long)]
+    module: String,
+
+    /// Path to a file containing the body passed to the module.
+    #[clap(long)]
+    input: PathBuf,
+
+    /// How the body is parsed, like the content type of a target's response.
+    #[clap(
+        long = "content-type",
+        default_value = "json",
+        possible_values = &["json", "string", "yaml"]
+    )]
+    content_type: ContentType,
+}
+
+#[derive(Clap)]
+struct Test {
+    /// Number of modules tested in parallel.
+    #[clap(long, default_value = "1")]
+    concurrency: usize,
+}
+
+#[derive(Clap)]
+struct RunOnce {
+    /// The module to probe.
+    #[clap(long)]
+    module: String,
+
+    /// The target to probe, defaults to the configured targets of the module.
+    #[clap(long)]
+    target: Option<String>,
+
+    /// How the body of the target is parsed, overriding the `content_type`
+    /// of the module and the Content-Type header of the response.
+    #[clap(long = "content-type", possible_values = &["json", "string", "yaml"])]
+    content_type: Option<ContentType>,
+}
+
+#[derive(Clap)]
+struct Bench {
+    /// The module to benchmark.
+    #[clap(long)]
+    module: String,
+
+    /// Path to a file containing the JSON body passed to the module.
+    #[clap(long)]
+    input: PathBuf,
+
+    /// Number of evaluations.
+    #[clap(long, default_value = "100")]
+    iterations: usize,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 struct Config {
     modules: HashMap<String, ConfigModule>,
+
+    /// Directory relative `jsonnet_path`s and imports are resolved against,
+    /// this is `--base-dir` or the directory containing the config file.
+    #[serde(skip)]
+    base_dir: PathBuf,
+
+    /// Directories searched for imports of all modules, after the library
+    /// paths of the module. These are `--jpath` followed by `JSONNET_PATH`.
+    #[serde(skip)]
+    jpath: Vec<PathBuf>,
+
+    /// How to handle background scraped modules defining the same metric
+    /// with a different help or type, or the same series.
+    #[serde(default)]
+    duplicate_metrics: DuplicateMetrics,
+
+    /// Labels rendered first and in this order, remaining labels follow
+    /// sorted by name.
+    #[serde(default)]
+    label_order: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DuplicateMetrics {
+    /// Fail the exposition with an error describing the conflict.
+    Error,
+    /// Prefix the conflicting metric with the module name.
+    Namespace,
+}
+
+impl Default for DuplicateMetrics {
+    fn default() -> Self {
+        DuplicateMetrics::Error
+    }
+}
+
+/// How the body of a target is parsed.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ContentType {
+    Json,
+    /// The body is passed to the module as string.
+    String,
+    Yaml,
+}
+
+impl std::str::FromStr for ContentType {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(ContentType::Json),
+            "string" => Ok(ContentType::String),
+            "yaml" => Ok(ContentType::Yaml),
+            _ => Err(format!("unknown content type '{}'", s)),
+        }
+    }
 }
 
 // TODO: Define error better
 type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
 impl Config {
-    fn validate(&self) -> Result<()> {
+    fn validate(&self, parsed: &ParseCache) -> Result<()> {
         for (name, module) in &self.modules {
+            if let Some(fallback) = &module.fallback {
+                if !self.modules.contains_key(fallback) {
+                    return Err(
+                        format!("module '{}' fallback '{}' not found", name, fallback).into(),
+                    );
+                }
+            }
+            // series of targets with the same labels would collide
+            let targets = module.targets.as_deref().unwrap_or_default();
+            for (i, target) in targets.iter().enumerate() {
+                if let Some(other) = targets[..i].iter().find(|t| t.labels == target.labels) {
+                    return Err(format!(
+                        "module '{}' targets '{}' and '{}' have the same labels",
+                        name, other.url, target.url
+                    )
+                    .into());
+                }
+            }
             module
-                .validate()
+                .validate(&self.base_dir, &self.jpath, parsed)
                 .map_err(|e| format!("module '{}' {:?}", name, e))?;
         }
         Ok(())
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 struct ConfigModule {
+    /// Describes what the module does, shown on `/modules` and `/`.
+    description: Option<String>,
+
     jsonnet_path: Option<String>,
     jsonnet: Option<String>,
     tests: Option<Vec<ConfigModuleTest>>,
+
+    /// Fixed set of targets, probed when no target is given, their labels
+    /// have to tell them apart. A failing target is reported as
+    /// `probe_success 0` with the labels of the target.
+    targets: Option<Vec<ConfigModuleTarget>>,
+
+    /// Scrape a target periodically and expose the result on `/metrics`.
+    background: Option<ConfigModuleBackground>,
+
+    /// Add `probe_samples_scraped` and `probe_samples_post_filtering` to the
+    /// exposition of every probe.
+    #[serde(default)]
+    meta_metrics: bool,
+
+    /// Expose the expiry of the certificate HTTPS targets answered with as
+    /// `probe_ssl_earliest_cert_expiry`, also for certificates failing
+    /// verification with `strict_tls`. The TLS library only exposes the leaf
+    /// certificate, the rest of the chain is not considered.
+    #[serde(default)]
+    tls_cert_expiry: bool,
+
+    /// Minimum interval between fetches of the same target, probes within
+    /// the interval are answered with the previous result.
+    min_interval_seconds: Option<u64>,
+
+    /// Check that test inputs are JSON objects with a `body`, like the input
+    /// of real probes, before running them.
+    #[serde(default)]
+    validate_test_input: bool,
+
+    /// Add an `instance` label with the `host:port` of the target to every
+    /// series, unless the module sets the label itself.
+    #[serde(default)]
+    instance_label: bool,
+
+    /// Omit the `# HELP` line of metrics without help, instead of emitting a
+    /// placeholder.
+    #[serde(default)]
+    omit_missing_help: bool,
+
+    /// Truncate response bodies larger than this to their first bytes and
+    /// expose the full size as `probe_body_size_bytes`. A truncated body is
+    /// always passed as string, JSON parsing is skipped.
+    max_body_bytes: Option<usize>,
+
+    /// Parse the response body as JSON array and evaluate the module for
+    /// each of its elements, up to this many elements. The elements are
+    /// parsed one at a time and the resulting metrics are merged.
+    stream_max_elements: Option<usize>,
+
+    /// Report failed TLS verification of the target, like self-signed or
+    /// expired certificates, as failed probe with a `reason` label instead of
+    /// a generic HTTP error. The reason is told from the certificate, which
+    /// is read again without verification.
+    #[serde(default)]
+    strict_tls: bool,
+
+    /// Add `probe_module_info` with the module name and a hash of its config
+    /// as version to every probe.
+    #[serde(default)]
+    module_info: bool,
+
+    /// Send a credential read from a file with every request to the target.
+    credentials: Option<ConfigModuleCredentials>,
+
+    /// Follow up to this many redirects of the target, redirects are not
+    /// followed when unset.
+    max_redirects: Option<usize>,
+
+    /// Allow redirects to another host than the one of the redirecting URL.
+    /// The credential of the module is not sent to other hosts.
+    #[serde(default)]
+    follow_cross_host: bool,
+
+    /// Add `probe_label_cardinality` with the number of distinct values of
+    /// every label. This is expensive for modules emitting many series.
+    #[serde(default)]
+    label_cardinality: bool,
+
+    /// Parse the body of the target as this content type, regardless of the
+    /// Content-Type header of the response. By default `application/json`
+    /// bodies are parsed as JSON, all others are passed as string.
+    content_type: Option<ContentType>,
+
+    /// Reject JSON bodies nesting arrays and objects deeper than this, before
+    /// they are parsed. Streamed bodies are checked while they are received.
+    max_json_depth: Option<usize>,
+
+    /// Transformations applied in order to the response body before it is
+    /// passed to the module. They are not applied to streamed bodies.
+    #[serde(default)]
+    transforms: Vec<transforms::Transform>,
+
+    /// Names of the metrics the module may emit, evaluations emitting other
+    /// metrics fail, including the tests of the module.
+    allowed_metrics: Option<Vec<String>>,
+
+    /// Reject targets resolving to loopback, private or link-local addresses,
+    /// including the targets of redirects. Hosts are checked as they are
+    /// resolved for the connection, so they can't resolve differently later.
+    #[serde(default)]
+    reject_private_targets: bool,
+
+    /// Hosts allowed even though they resolve to private addresses.
+    #[serde(default)]
+    allowed_private_hosts: Vec<String>,
+
+    /// Directories searched in order for imports not found relative to the
+    /// importing file, relative to the base directory. Defaults to the base
+    /// directory.
+    #[serde(default)]
+    library_paths: Vec<String>,
+
+    /// Extensions appended to imports which can't be found as is, tried in
+    /// order, e.g. `.libsonnet`.
+    #[serde(default)]
+    import_extensions: Vec<String>,
+
+    /// Number of evaluation results kept per module, identical inputs reuse
+    /// the result instead of being evaluated again. Only suitable for modules
+    /// which result depends on nothing but their input, e.g. not on `now()`.
+    /// Streamed bodies are not cached.
+    eval_cache_size: Option<usize>,
+
+    /// Module evaluated instead, when the evaluation of this module fails.
+    /// It gets the same input, with the error added as `error`.
+    fallback: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct ConfigModuleBackground {
+    /// Target to scrape, defaults to the configured `targets` of the module.
+    target: Option<String>,
+
+    #[serde(default = "default_background_interval_seconds")]
+    interval_seconds: u64,
+
+    /// Exposition group of the module, grouped modules are served on
+    /// `/metrics/{group}` instead of `/metrics`.
+    group: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct ConfigModuleTarget {
+    url: String,
+
+    /// Labels attached to every series of the target.
+    #[serde(default)]
+    labels: HashMap<String, String>,
+}
+
+fn default_background_interval_seconds() -> u64 {
+    60
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct ConfigModuleCredentials {
+    /// File containing the credential, relative to the base directory. The
+    /// file is re-read periodically, so rotated secrets are picked up.
+    file: String,
+
+    #[serde(default = "default_credentials_header")]
+    header: String,
+
+    /// Prepended to the content of the file, e.g. `Bearer `.
+    #[serde(default)]
+    prefix: String,
+
+    #[serde(default = "default_credentials_refresh_seconds")]
+    refresh_seconds: u64,
+}
+
+fn default_credentials_header() -> String {
+    "Authorization".to_string()
+}
+
+fn default_credentials_refresh_seconds() -> u64 {
+    60
 }
 
 // MemoryImportResolver allows to import a single other file from memory
@@ -125,45 +584,78 @@ impl ImportResolver for MemoryImportResolver {
     }
 }
 
+/// Resolves imports relative to the importing file, then in each library
+/// path in order. Every candidate is tried as is and with each of the
+/// extensions appended.
+#[derive(Debug)]
+struct LibraryImportResolver {
+    library_paths: Vec<PathBuf>,
+    extensions: Vec<String>,
+}
+
+impl ImportResolver for LibraryImportResolver {
+    fn resolve_file(
+        &self,
+        from: &PathBuf,
+        path: &PathBuf,
+    ) -> jrsonnet_evaluator::error::Result<Rc<PathBuf>> {
+        let mut tried = vec![];
+        for root in std::iter::once(from).chain(self.library_paths.iter()) {
+            let candidate = root.join(path);
+            let with_extensions = self.extensions.iter().map(|ext| {
+                let mut file_name = candidate.as_os_str().to_owned();
+                file_name.push(ext);
+                PathBuf::from(file_name)
+            });
+            for candidate in std::iter::once(candidate.clone()).chain(with_extensions) {
+                if candidate.is_file() {
+                    return Ok(Rc::new(candidate));
+                }
+                tried.push(candidate.display().to_string());
+            }
+        }
+        throw!(jrsonnet_evaluator::error::Error::RuntimeError(
+            format!(
+                "cannot resolve import '{}', tried: {}",
+                path.display(),
+                tried.join(", ")
+            )
+            .into()
+        ))
+    }
+
+    fn load_file_contents(&self, resolved: &PathBuf) -> jrsonnet_evaluator::error::Result<IStr> {
+        match std::fs::read_to_string(resolved) {
+            Ok(contents) => Ok(contents.into()),
+            Err(_) => throw!(jrsonnet_evaluator::error::Error::ImportFileNotFound(
+                resolved.clone(),
+                resolved.clone()
+            )),
+        }
+    }
+
+    unsafe fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
 impl ConfigModule {
-    fn state(&self) -> Result<Module> {
+    /// Sets up the evaluation of the module, imports not found relative to
+    /// the importing file are searched in its library paths, then in
+    /// `jpath`.
+    fn state(
+        &self,
+        base_dir: &Path,
+        jpath: &[PathBuf],
+        parsed: &ParseCache,
+        clock: Clock,
+    ) -> Result<Module> {
         let state = EvaluationState::default();
         state.with_stdlib();
 
-        let cb = Rc::new(NativeCallback::new(
-            ParamsDesc(Rc::new(vec![
-                Param("regex".into(), None),
-                Param("string".into(), None),
-            ])),
-            |_caller, args| match (&args[0], &args[1]) {
-                (Val::Str(regex), Val::Str(string)) => {
-                    let re = Regex::new(regex).unwrap();
-
-                    let matches: Vec<Val> = re
-                        .captures_iter(string)
-                        .into_iter()
-                        .map(|capture| {
-                            let val: Vec<Val> = capture
-                                .iter()
-                                .filter_map(|submatch| match submatch {
-                                    Some(m) => Some(Val::Str(m.as_str().into())),
-                                    None => Some(Val::Null),
-                                })
-                                .collect();
-                            Val::Arr(val.into())
-                        })
-                        .collect();
-
-                    debug!("native call regexMatch={:?}", matches);
-
-                    Ok(Val::Arr(matches.into()))
-                }
-                (_, _) => unreachable!(),
-            },
-        ));
+        natives::add_natives(&state, clock);
 
-        state.add_native("regexMatch".into(), cb);
-        let (path, jsonnet) = match (&self.jsonnet, &self.jsonnet_path) {
+        let (path, jsonnet): (Rc<PathBuf>, IStr) = match (&self.jsonnet, &self.jsonnet_path) {
             (Some(_), Some(_)) => Err("Only one of 'jsonnet' and 'jsonnet_path' can be set"),
             (None, None) => Err("One of 'jsonnet' or 'jsonnet_path' has to be set"),
             (Some(jsonnet), None) => {
@@ -178,337 +670,4959 @@ impl ConfigModule {
                 Ok((Rc::new(PathBuf::from(path)), jsonnet.to_owned().into()))
             }
             (None, Some(jsonnet_file)) => {
-                let path = Rc::new(PathBuf::from(jsonnet_file));
-                let mut file = File::open(jsonnet_file)?;
+                let path = Rc::new(base_dir.join(jsonnet_file));
+                let mut file = File::open(path.as_ref())?;
                 let mut out = String::new();
                 file.read_to_string(&mut out)?;
 
-                // TODO import differently configured _jpath
-                state.set_import_resolver(Box::new(FileImportResolver {
-                    library_paths: vec![],
-                }));
+                let mut library_paths: Vec<PathBuf> = if self.library_paths.is_empty() {
+                    vec![base_dir.to_path_buf()]
+                } else {
+                    self.library_paths
+                        .iter()
+                        .map(|p| base_dir.join(p))
+                        .collect()
+                };
+                library_paths.extend(jpath.iter().cloned());
+
+                if self.library_paths.is_empty() && self.import_extensions.is_empty() {
+                    state.set_import_resolver(Box::new(FileImportResolver { library_paths }));
+                } else {
+                    state.set_import_resolver(Box::new(LibraryImportResolver {
+                        library_paths,
+                        extensions: self.import_extensions.clone(),
+                    }));
+                }
 
                 Ok((path.clone(), out.into()))
             }
         }?;
 
-        state.add_file(path.clone(), jsonnet).map_err(|e| match e {
-            e => format!("err {:?}", e),
-        })?;
+        let parsed = parsed.parse(&path, &jsonnet)?;
+        state
+            .add_parsed_file(path.clone(), jsonnet, parsed)
+            .map_err(|e| format!("err {:?}", e))?;
 
         Ok(Module {
-            state: state,
-            path: path,
+            state,
+            path,
+            meta_metrics: self.meta_metrics,
+            omit_missing_help: self.omit_missing_help,
+            label_cardinality: self.label_cardinality,
+            allowed_metrics: self.allowed_metrics.clone(),
+            clock,
         })
     }
 
-    fn validate(&self) -> Result<()> {
-        // TODO        state.set_manifest_format(jrsonnet_evaluator::ManifestFormat::Json(3));
-        let module = self.state()?;
+    /// Fetches the target and returns the input data for the module.
+    async fn fetch(
+        &self,
+        target: &str,
+        mut credential: Option<(String, String)>,
+    ) -> std::result::Result<Fetched, ProbeError> {
+        let mut uri: hyper::Uri = target.parse().map_err(ProbeError::InvalidTargetUrl)?;
+        let mut redirects = 0;
 
-        // TODO move into subcommand
-        if let Some(tests) = &self.tests {
-            for test in tests.iter() {
-                info!("test: {:?}", test);
-                let actual = module.eval(&test.input)?;
+        // Await the response...
+        use hyper::Client;
+        let connector = CertificateConnector::new(self.resolver());
+        let certificate = connector.certificate.clone();
+        // connections aren't reused, so the certificate is the one of the
+        // connection the response was received on
+        let client = Client::builder()
+            .pool_max_idle_per_host(0)
+            .build::<_, hyper::Body>(connector);
+        let resp = loop {
+            self.check_target_address(&uri).await?;
 
-                if actual == test.output {
-                    debug!("test of module TODO.# passed")
-                } else {
-                    let actual_lines = actual.split("\n").collect::<Vec<&str>>();
-                    let expected_lines = test.output.split("\n").collect::<Vec<&str>>();
-                    error!(
-                        "test of module TODO.# failed:\n\
-                  {}\n\
-                  ",
-                        Comparison::new(&actual_lines, &expected_lines)
-                    );
-                }
+            let mut req = hyper::Request::get(uri.clone());
+            if let Some((header, value)) = &credential {
+                req = req.header(header.as_str(), value.as_str());
             }
-        };
+            let req = req
+                .body(hyper::Body::empty())
+                .map_err(|e| ProbeError::Credentials(e.to_string()))?;
 
-        //
+            let resp = match client.request(req).await {
+                Ok(resp) => resp,
+                Err(e) => return Err(self.http_error(&uri, e).await),
+            };
+            let max_redirects = match self.max_redirects {
+                Some(max_redirects) if resp.status().is_redirection() => max_redirects,
+                _ => break resp,
+            };
+            let location = resp
+                .headers()
+                .get(LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string);
+            let location = match location {
+                Some(location) => location,
+                None => break resp,
+            };
 
-        Ok(())
-    }
-}
+            redirects += 1;
+            if redirects > max_redirects {
+                return Err(ProbeError::TooManyRedirects(max_redirects));
+            }
+            let next = self.redirect_target(&uri, &location)?;
+            if !same_origin(&next, &uri) {
+                // never leak the credential of the target to another origin,
+                // including a downgrade to plain HTTP
+                credential = None;
+            }
+            debug!("following redirect from {} to {}", uri, next);
+            uri = next;
+        };
+        let cert_expiry = match &*certificate.lock().unwrap() {
+            Some(der) if self.tls_cert_expiry => certificate_expiry(der),
+            _ => None,
+        };
+        if self.stream_max_elements.is_some() {
+            info!("streamed response");
+            // the body is parsed while it is received
+            return Ok(Fetched {
+                data: serde_json::Value::Null,
+                stream: Some(resp.into_body()),
+                body_size: 0,
+                cert_expiry,
+            });
+        }
 
-#[derive(Serialize, Deserialize)]
-struct InputData {
-    body: serde_json::Value,
-}
+        let headers = &resp.headers().clone();
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
-struct ConfigModuleTest {
-    input: String,
-    output: String,
-}
+        let mut body = hyper::body::aggregate(resp)
+            .await
+            .map_err(ProbeError::TargetHTTP)?;
 
-#[derive(serde::Deserialize, Debug)]
-enum MetricType {
-    #[serde(rename = "gauge")]
-    Gauge,
-}
+        let body_size = body.remaining();
 
-#[derive(serde::Deserialize, Debug)]
-struct Metric {
-    label_names: Option<Vec<String>>,
-    #[serde(default)]
-    series: Vec<Series>,
-    help: Option<String>,
-    r#type: MetricType,
-}
+        let content_type = match (self.content_type, headers.get(CONTENT_TYPE)) {
+            (Some(content_type), _) => content_type,
+            (None, Some(header_value))
+                if header_value == HeaderValue::from_static("application/json") =>
+            {
+                ContentType::Json
+            }
+            _ => ContentType::String,
+        };
+        let json_body: serde_json::Value = match self.max_body_bytes {
+            Some(max_body_bytes) if body_size > max_body_bytes => {
+                info!("truncated response of {} bytes", body_size);
+                let mut buffer = vec![];
+                body.reader()
+                    .take(max_body_bytes as u64)
+                    .read_to_end(&mut buffer)
+                    .unwrap();
+                serde_json::Value::String(String::from_utf8_lossy(&buffer).into_owned())
+            }
+            _ => {
+                info!("{:?} response", content_type);
+                self.parse_body(content_type, &body.copy_to_bytes(body_size))?
+            }
+        };
 
-#[derive(serde::Deserialize, Debug)]
-struct Metrics(HashMap<String, Metric>);
+        let json_body =
+            transforms::apply(&self.transforms, json_body).map_err(ProbeError::Transform)?;
 
-#[derive(serde::Deserialize, Debug)]
-struct Series {
-    label_values: Option<Vec<String>>,
-    value: f64,
-}
+        Ok(Fetched {
+            data: serde_json::json!({ "body": json_body }),
+            stream: None,
+            body_size,
+            cert_expiry,
+        })
+    }
 
-#[derive(Debug)]
-struct MissingQueryParameter {
-    name: String,
-}
+    /// Resolves the host of the target and rejects it, if any of its
+    /// addresses is private, unless the host is explicitly allowed.
+    async fn check_target_address(&self, uri: &hyper::Uri) -> std::result::Result<(), ProbeError> {
+        if !self.reject_private_targets {
+            return Ok(());
+        }
+        let host = uri.host().unwrap_or_default();
+        if self
+            .allowed_private_hosts
+            .iter()
+            .any(|allowed| allowed == host)
+        {
+            return Ok(());
+        }
 
-#[derive(Debug)]
-enum ProbeError {
-    MissingParameter(String),
-    ModuleNotFound(String),
-    InvalidTargetUrl(warp::http::uri::InvalidUri),
-    TargetHTTP(hyper::Error),
-    TargetJSONParse(serde_json::Error),
-}
+        let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+            Some("https") => 443,
+            _ => 80,
+        });
+        // IPv6 hosts are bracketed in URIs
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        self.resolver()
+            .resolve(host.to_string(), port)
+            .await
+            .map(|_| ())
+            .map_err(|e| resolve_error(host, e))
+    }
 
-impl warp::reject::Reject for MissingQueryParameter {}
+    /// Returns the resolver for connections to the targets of the module.
+    fn resolver(&self) -> TargetResolver {
+        TargetResolver {
+            reject_private_targets: self.reject_private_targets,
+            allowed_private_hosts: Arc::new(self.allowed_private_hosts.clone()),
+        }
+    }
 
-impl warp::reject::Reject for ProbeError {}
+    /// Parses a body as the given content type into the value passed to the
+    /// module.
+    fn parse_body(
+        &self,
+        content_type: ContentType,
+        body: &[u8],
+    ) -> std::result::Result<serde_json::Value, ProbeError> {
+        match content_type {
+            ContentType::Json => {
+                self.check_json_depth(body)?;
+                serde_json::from_slice(body).map_err(ProbeError::TargetJSONParse)
+            }
+            ContentType::Yaml => serde_yaml::from_slice(body).map_err(ProbeError::TargetYAMLParse),
+            ContentType::String => Ok(serde_json::Value::String(
+                String::from_utf8_lossy(body).into_owned(),
+            )),
+        }
+    }
 
-async fn metrics_handler() -> std::result::Result<impl Reply, Rejection> {
-    let encoder = TextEncoder::new();
+    fn check_json_depth(&self, body: &[u8]) -> std::result::Result<(), ProbeError> {
+        match self.max_json_depth {
+            Some(max_depth) if json_depth_exceeds(body, max_depth) => {
+                Err(ProbeError::TargetJSONDepth(max_depth))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Resolves the location of a redirect relative to the redirecting URI.
+    fn redirect_target(
+        &self,
+        uri: &hyper::Uri,
+        location: &str,
+    ) -> std::result::Result<hyper::Uri, ProbeError> {
+        let next = url::Url::parse(&uri.to_string())
+            .and_then(|base| base.join(location))
+            .map_err(|e| ProbeError::Redirect(format!("invalid location '{}': {}", location, e)))?;
+        let next: hyper::Uri = next
+            .as_str()
+            .parse()
+            .map_err(ProbeError::InvalidTargetUrl)?;
+
+        if !self.follow_cross_host && next.host() != uri.host() {
+            return Err(ProbeError::Redirect(format!(
+                "redirect to other host '{}' is not allowed",
+                next.host().unwrap_or_default()
+            )));
+        }
+        Ok(next)
+    }
+
+    /// Returns a short hash of the module config, which changes with every
+    /// change of the module.
+    fn version(&self) -> String {
+        // a JSON value has its object keys sorted, so the hash is stable
+        let config = serde_json::to_value(self)
+            .map(|v| v.to_string())
+            .unwrap_or_default();
+        let hash = format!("{:x}", Sha256::digest(config.as_bytes()));
+        hash[..12].to_string()
+    }
+
+    async fn http_error(&self, uri: &hyper::Uri, e: hyper::Error) -> ProbeError {
+        let private = std::iter::successors(e.source(), |e| e.source())
+            .find_map(|e| e.downcast_ref::<PrivateAddress>());
+        if let Some(private) = private {
+            return ProbeError::PrivateTarget(private.0.clone());
+        }
+        if !self.strict_tls || !is_tls_error(&e) {
+            return ProbeError::TargetHTTP(e);
+        }
+        let now = chrono::Utc::now().timestamp();
+        match self.unverified_certificate(uri).await {
+            Ok(der) => ProbeError::TargetTLSVerification(
+                certificate_failure_reason(&der, uri.host().unwrap_or_default(), now).into(),
+                if self.tls_cert_expiry {
+                    certificate_expiry(&der)
+                } else {
+                    None
+                },
+            ),
+            Err(e) => {
+                debug!("cannot read certificate of {}: {:?}", uri, e);
+                ProbeError::TargetTLSVerification("other".into(), None)
+            }
+        }
+    }
+
+    /// Connects to the target without verifying its certificate and returns
+    /// the certificate it presents in DER.
+    async fn unverified_certificate(
+        &self,
+        uri: &hyper::Uri,
+    ) -> std::result::Result<Vec<u8>, ProbeError> {
+        let host = uri
+            .host()
+            .ok_or_else(|| ProbeError::TargetTLS("target has no host".into()))?;
+        let port = uri.port_u16().unwrap_or(443);
+
+        // connect to the checked addresses, the host may resolve differently
+        // on another lookup
+        let addrs = self
+            .resolver()
+            .resolve(
+                host.trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .to_string(),
+                port,
+            )
+            .await
+            .map_err(|e| resolve_error(host, e))?;
+        let stream = tokio::net::TcpStream::connect(&addrs[..])
+            .await
+            .map_err(|e| ProbeError::TargetTLS(e.to_string()))?;
+        let connector = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true)
+            .build()
+            .map_err(|e| ProbeError::TargetTLS(e.to_string()))?;
+        let stream = tokio_native_tls::TlsConnector::from(connector)
+            .connect(host, stream)
+            .await
+            .map_err(|e| ProbeError::TargetTLS(e.to_string()))?;
+
+        let cert = stream
+            .get_ref()
+            .peer_certificate()
+            .map_err(|e| ProbeError::TargetTLS(e.to_string()))?
+            .ok_or_else(|| ProbeError::TargetTLS("target presented no certificate".into()))?;
+        cert.to_der()
+            .map_err(|e| ProbeError::TargetTLS(e.to_string()))
+    }
+
+    fn validate(&self, base_dir: &Path, jpath: &[PathBuf], parsed: &ParseCache) -> Result<()> {
+        // TODO        state.set_manifest_format(jrsonnet_evaluator::ManifestFormat::Json(3));
+        if self.jsonnet.is_some()
+            && (!self.library_paths.is_empty() || !self.import_extensions.is_empty())
+        {
+            return Err(
+                "inline 'jsonnet' can't import, 'library_paths' and 'import_extensions' are unsupported"
+                    .into(),
+            );
+        }
+        let tests = self.tests.as_deref().unwrap_or_default();
+        for result in self.run_tests(base_dir, jpath, parsed)? {
+            if result.passed {
+                debug!("test #{} passed", result.test);
+                continue;
+            }
+            // the terminal gets a coloured side by side diff
+            let actual: Vec<&str> = result.actual.split('\n').collect();
+            let expected: Vec<&str> = tests[result.test].output.split('\n').collect();
+            error!(
+                "test #{} failed:\n\
+                  {}\n\
+                  ",
+                result.test,
+                Comparison::new(&actual, &expected)
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Runs the tests of the module, errors evaluating a test fail the whole
+    /// run.
+    fn run_tests(
+        &self,
+        base_dir: &Path,
+        jpath: &[PathBuf],
+        parsed: &ParseCache,
+    ) -> Result<Vec<TestResult>> {
+        let module = self.state(base_dir, jpath, parsed, Clock::System)?;
+        let mut results = vec![];
+
+        if let Some(tests) = &self.tests {
+            for (i, test) in tests.iter().enumerate() {
+                info!("test: {:?}", test);
+
+                if self.validate_test_input {
+                    serde_json::from_str::<InputData>(&test.input).map_err(|e| {
+                        format!(
+                            "test #{} input is not valid JSON of the form {{\"body\": ...}}: {}",
+                            i, e
+                        )
+                    })?;
+                }
+                let mut input = module.eval_input(&test.input)?;
+                if let Some(body) = input.get_mut("body") {
+                    *body = transforms::apply(&self.transforms, body.take())?;
+                }
+                let actual = match test.clock {
+                    Some(clock) => self
+                        .state(base_dir, jpath, parsed, Clock::Fixed(clock))?
+                        .eval_families(&input)?,
+                    None => module.eval_families(&input)?,
+                };
+                let actual = encode_families(&actual)?;
+
+                let diff = diff_output(&actual, &test.output);
+                results.push(TestResult {
+                    test: i,
+                    passed: diff.is_none(),
+                    diff,
+                    actual,
+                });
+            }
+        };
+
+        Ok(results)
+    }
+}
+
+/// Entry of the module listing on `/modules`.
+#[derive(Serialize)]
+struct ModuleInfo {
+    name: String,
+    description: Option<String>,
+}
+
+/// Test results of a module, reported on `/tests`.
+#[derive(Serialize)]
+struct TestReport {
+    module: String,
+    /// Error preventing the tests of the module from running.
+    error: Option<String>,
+    tests: Vec<TestResult>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct TestResult {
+    test: usize,
+    passed: bool,
+    /// Diff between the actual and the expected output of a failed test.
+    diff: Option<String>,
+    /// Output of the test, the expected one is part of the config.
+    #[serde(skip)]
+    actual: String,
+}
+
+/// Compares the output of a test line by line, returns a plain diff when it
+/// differs from the expected output. Lines only expected are prefixed with
+/// `-`, lines only in the actual output with `+`.
+fn diff_output(actual: &str, expected: &str) -> Option<String> {
+    if actual == expected {
+        return None;
+    }
+    // split rather than `diff::lines`, which drops a trailing empty line
+    let actual: Vec<&str> = actual.split('\n').collect();
+    let expected: Vec<&str> = expected.split('\n').collect();
+
+    let mut diff = String::new();
+    for line in diff::slice(&expected, &actual) {
+        match line {
+            diff::Result::Left(l) => diff.push_str(&format!("-{}\n", l)),
+            diff::Result::Both(l, _) => diff.push_str(&format!(" {}\n", l)),
+            diff::Result::Right(r) => diff.push_str(&format!("+{}\n", r)),
+        }
+    }
+    Some(diff)
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Input of a module fetched from a target.
+struct Fetched {
+    data: serde_json::Value,
+    /// Body of a JSON array, which elements are evaluated one at a time
+    /// while it is received.
+    stream: Option<hyper::Body>,
+    /// Size of the response body, before any truncation. Streamed bodies are
+    /// counted while they are evaluated.
+    body_size: usize,
+    /// Expiry of the certificate of the target as unix timestamp, if
+    /// requested.
+    cert_expiry: Option<f64>,
+}
+
+/// Connects to targets like `HttpsConnector`, recording the certificate of
+/// the last connection.
+#[derive(Clone)]
+struct CertificateConnector {
+    https: hyper_tls::HttpsConnector<hyper::client::HttpConnector<TargetResolver>>,
+    /// Leaf certificate of the last connection in DER, `None` for plain HTTP.
+    certificate: Arc<Mutex<Option<Vec<u8>>>>,
+}
+
+impl CertificateConnector {
+    fn new(resolver: TargetResolver) -> Self {
+        let mut http = hyper::client::HttpConnector::new_with_resolver(resolver);
+        http.enforce_http(false);
+        CertificateConnector {
+            https: hyper_tls::HttpsConnector::new_with_connector(http),
+            certificate: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+/// Error of `TargetResolver` for a host resolving to a private address.
+#[derive(Debug)]
+struct PrivateAddress(String);
+
+impl std::fmt::Display for PrivateAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for PrivateAddress {}
+
+/// Resolves the hosts of targets. Hosts resolving to a private address fail
+/// if the module rejects private targets, unless they are explicitly
+/// allowed. IP addresses in URIs aren't resolved, they are checked by
+/// `check_target_address`.
+#[derive(Clone)]
+struct TargetResolver {
+    reject_private_targets: bool,
+    allowed_private_hosts: Arc<Vec<String>>,
+}
+
+impl TargetResolver {
+    async fn resolve(
+        self,
+        host: String,
+        port: u16,
+    ) -> std::result::Result<Vec<SocketAddr>, Box<dyn Error + Send + Sync>> {
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), port))
+            .await?
+            .collect();
+        if !self.reject_private_targets || self.allowed_private_hosts.contains(&host) {
+            return Ok(addrs);
+        }
+        match addrs.iter().find(|addr| is_private_address(&addr.ip())) {
+            Some(addr) => Err(Box::new(PrivateAddress(format!(
+                "'{}' resolves to private address {}",
+                host,
+                addr.ip()
+            )))),
+            None => Ok(addrs),
+        }
+    }
+}
+
+impl hyper::service::Service<hyper::client::connect::dns::Name> for TargetResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = std::pin::Pin<
+        Box<
+            dyn std::future::Future<Output = std::result::Result<Self::Response, Self::Error>>
+                + Send,
+        >,
+    >;
+
+    fn poll_ready(
+        &mut self,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::result::Result<(), Self::Error>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: hyper::client::connect::dns::Name) -> Self::Future {
+        let resolver = self.clone();
+        // the connector sets the port of the target
+        Box::pin(async move {
+            Ok(resolver
+                .resolve(name.as_str().to_string(), 0)
+                .await?
+                .into_iter())
+        })
+    }
+}
+
+/// Converts an error of `TargetResolver` into the error of the probe.
+fn resolve_error(host: &str, e: Box<dyn Error + Send + Sync>) -> ProbeError {
+    match e.downcast::<PrivateAddress>() {
+        Ok(private) => ProbeError::PrivateTarget(private.0),
+        Err(e) => ProbeError::PrivateTarget(format!("cannot resolve '{}': {}", host, e)),
+    }
+}
+
+impl hyper::service::Service<hyper::Uri> for CertificateConnector {
+    type Response = hyper_tls::MaybeHttpsStream<tokio::net::TcpStream>;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = std::pin::Pin<
+        Box<
+            dyn std::future::Future<Output = std::result::Result<Self::Response, Self::Error>>
+                + Send,
+        >,
+    >;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::result::Result<(), Self::Error>> {
+        hyper::service::Service::poll_ready(&mut self.https, cx)
+    }
+
+    fn call(&mut self, uri: hyper::Uri) -> Self::Future {
+        let connecting = hyper::service::Service::call(&mut self.https, uri);
+        let certificate = self.certificate.clone();
+        Box::pin(async move {
+            let stream = connecting.await?;
+            *certificate.lock().unwrap() = match &stream {
+                hyper_tls::MaybeHttpsStream::Https(tls) => tls
+                    .get_ref()
+                    .peer_certificate()
+                    .ok()
+                    .flatten()
+                    .and_then(|cert| cert.to_der().ok()),
+                hyper_tls::MaybeHttpsStream::Http(_) => None,
+            };
+            Ok(stream)
+        })
+    }
+}
+
+/// Reads a response body while it is received, for parsing it with a
+/// blocking reader within `tokio::task::block_in_place`. Reading fails once
+/// the body nests JSON deeper than the maximum depth.
+///
+/// Chunks are awaited with `Handle::block_on`, which like `block_in_place`
+/// requires the multi threaded runtime, both panic on a current thread one.
+struct BodyReader {
+    body: hyper::Body,
+    chunk: bytes::Bytes,
+    max_json_depth: Option<usize>,
+    depth: JsonDepth,
+    /// Number of bytes received.
+    size: usize,
+}
+
+impl BodyReader {
+    fn new(body: hyper::Body, max_json_depth: Option<usize>) -> Self {
+        BodyReader {
+            body,
+            chunk: bytes::Bytes::new(),
+            max_json_depth,
+            depth: JsonDepth::default(),
+            size: 0,
+        }
+    }
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while !self.chunk.has_remaining() {
+            let chunk = tokio::runtime::Handle::current()
+                .block_on(hyper::body::HttpBody::data(&mut self.body));
+            self.chunk = match chunk {
+                Some(chunk) => {
+                    chunk.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+                }
+                None => return Ok(0),
+            };
+            self.size += self.chunk.len();
+
+            if let Some(max_depth) = self.max_json_depth {
+                if self.depth.exceeds(&self.chunk, max_depth) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("{:?}", ProbeError::TargetJSONDepth(max_depth)),
+                    ));
+                }
+            }
+        }
+
+        let len = buf.len().min(self.chunk.remaining());
+        self.chunk.copy_to_slice(&mut buf[..len]);
+        Ok(len)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct InputData {
+    body: serde_json::Value,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct ConfigModuleTest {
+    /// Input of the module in JSON or jsonnet syntax, like `{"body": ...}`.
+    input: String,
+    output: String,
+    /// Fix the time returned by the `now()` native to this unix timestamp.
+    clock: Option<f64>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+enum MetricType {
+    #[serde(rename = "gauge")]
+    Gauge,
+    #[serde(rename = "histogram")]
+    Histogram,
+    #[serde(rename = "counter")]
+    Counter,
+    #[serde(rename = "untyped")]
+    Untyped,
+}
+
+const MISSING_HELP: &str =
+    "jsonnet-exporter: Metric help is missing, consider adding a help text to the module config.";
+
+#[derive(serde::Deserialize, Debug)]
+struct Metric {
+    label_names: Option<Vec<String>>,
+    #[serde(default)]
+    series: Vec<Series>,
+    help: Option<String>,
+    r#type: MetricType,
+    /// Upper bounds of the histogram buckets, without `+Inf`.
+    buckets: Option<Vec<f64>>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct Metrics(HashMap<String, Metric>);
+
+#[derive(serde::Deserialize, Debug)]
+struct Series {
+    label_values: Option<Vec<String>>,
+    /// Value of the series, only histograms omit it.
+    value: Option<f64>,
+    /// Cumulative counts of a histogram, aligned to the metric's `buckets`.
+    bucket_counts: Option<Vec<u64>>,
+    /// Total count of a histogram, defaults to the count of the last bucket.
+    count: Option<u64>,
+    #[serde(default)]
+    sum: f64,
+    /// Creation time of a counter in milliseconds since epoch.
+    created_ms: Option<f64>,
+    /// Signals that a counter has been reset, which sets its creation time to
+    /// now.
+    #[serde(default)]
+    reset: bool,
+    /// Exemplar of a counter or histogram, only exposed in the OpenMetrics
+    /// format.
+    exemplar: Option<Exemplar>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct Exemplar {
+    value: f64,
+    #[serde(default)]
+    labels: HashMap<String, String>,
+    /// Unix timestamp in seconds.
+    timestamp: Option<f64>,
+}
+
+impl Series {
+    fn label_values(&self) -> Vec<&str> {
+        match &self.label_values {
+            Some(lv) => lv.iter().map(std::ops::Deref::deref).collect(),
+            None => vec![],
+        }
+    }
+
+    fn value(&self, metric_name: &str) -> Result<f64> {
+        match self.value {
+            Some(value) => Ok(value),
+            None => Err(format!("series of metric '{}' has no value", metric_name).into()),
+        }
+    }
+}
+
+/// Builds the family carrying the exemplars of the series of a counter or
+/// histogram, see `openmetrics::exemplar_name`.
+fn exemplar_family(name: &str, metric: &Metric) -> Result<Option<MetricFamily>> {
+    if metric.series.iter().all(|s| s.exemplar.is_none()) {
+        return Ok(None);
+    }
+    match metric.r#type {
+        MetricType::Counter | MetricType::Histogram => {}
+        _ => return Err(format!("metric '{}' can't have exemplars", name).into()),
+    }
+    let label_names = metric.label_names.clone().unwrap_or_default();
+
+    let mut mf = gauge_family(&openmetrics::exemplar_name(name), "", &[], 0.0);
+    mf.mut_metric().clear();
+
+    for s in &metric.series {
+        let exemplar = match &s.exemplar {
+            Some(exemplar) => exemplar,
+            None => continue,
+        };
+        let length: usize = exemplar
+            .labels
+            .iter()
+            .map(|(name, value)| name.chars().count() + value.chars().count())
+            .sum();
+        if length > openmetrics::EXEMPLAR_MAX_LABEL_LENGTH {
+            return Err(format!(
+                "metric '{}' exemplar labels have {} characters, more than {}",
+                name,
+                length,
+                openmetrics::EXEMPLAR_MAX_LABEL_LENGTH
+            )
+            .into());
+        }
+
+        // labels in the order of the sample carrying the exemplar
+        let mut labels: Vec<(&str, String)> = label_names
+            .iter()
+            .map(String::as_str)
+            .zip(s.label_values().into_iter().map(str::to_string))
+            .collect();
+        if let MetricType::Counter = metric.r#type {
+            labels.sort();
+        }
+        let mut exemplar_labels: Vec<(String, &str)> = exemplar
+            .labels
+            .iter()
+            .map(|(name, value)| {
+                (
+                    format!("{}{}", openmetrics::EXEMPLAR_LABEL_PREFIX, name),
+                    value.as_str(),
+                )
+            })
+            .collect();
+        exemplar_labels.sort();
+
+        let labels: Vec<(&str, &str)> = labels
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .chain(
+                exemplar_labels
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), *value)),
+            )
+            .collect();
+        let mut m = gauge_family("", "", &labels, exemplar.value)
+            .take_metric()
+            .remove(0);
+        if let Some(timestamp) = exemplar.timestamp {
+            m.set_timestamp_ms((timestamp * 1000.0) as i64);
+        }
+        mf.mut_metric().push(m);
+    }
+    Ok(Some(mf))
+}
+
+/// Builds a histogram family out of pre-bucketed cumulative counts, the
+/// encoder renders the `+Inf` bucket from the series count.
+fn histogram_family(name: &str, metric: &Metric) -> Result<MetricFamily> {
+    let buckets = match &metric.buckets {
+        Some(buckets) => buckets,
+        None => return Err(format!("histogram '{}' has no buckets", name).into()),
+    };
+    if buckets.windows(2).any(|w| w[0] >= w[1]) {
+        return Err(format!("histogram '{}' buckets are not increasing", name).into());
+    }
+    let label_names = metric.label_names.clone().unwrap_or_default();
+
+    let mut mf = MetricFamily::default();
+    mf.set_name(name.to_string());
+    mf.set_help(metric.help.as_deref().unwrap_or(MISSING_HELP).to_string());
+    mf.set_field_type(prometheus::proto::MetricType::HISTOGRAM);
+
+    for s in &metric.series {
+        let counts = match &s.bucket_counts {
+            Some(counts) => counts,
+            None => return Err(format!("histogram '{}' series has no bucket_counts", name).into()),
+        };
+        if counts.len() != buckets.len() {
+            return Err(format!(
+                "histogram '{}' has {} buckets, but {} bucket_counts",
+                name,
+                buckets.len(),
+                counts.len()
+            )
+            .into());
+        }
+        if counts.windows(2).any(|w| w[0] > w[1]) {
+            return Err(format!("histogram '{}' bucket_counts are not cumulative", name).into());
+        }
+        let last = counts.last().copied().unwrap_or(0);
+        let count = s.count.unwrap_or(last);
+        if count < last {
+            return Err(format!("histogram '{}' count is lower than its buckets", name).into());
+        }
+
+        let mut histogram = prometheus::proto::Histogram::default();
+        histogram.set_sample_count(count);
+        histogram.set_sample_sum(s.sum);
+        for (upper_bound, cumulative_count) in buckets.iter().zip(counts) {
+            let mut bucket = prometheus::proto::Bucket::default();
+            bucket.set_upper_bound(*upper_bound);
+            bucket.set_cumulative_count(*cumulative_count);
+            histogram.mut_bucket().push(bucket);
+        }
+
+        let label_values = s.label_values.clone().unwrap_or_default();
+        if label_values.len() != label_names.len() {
+            return Err(format!("histogram '{}' has inconsistent label cardinality", name).into());
+        }
+
+        let mut m = prometheus::proto::Metric::default();
+        m.set_histogram(histogram);
+        for (label_name, label_value) in label_names.iter().zip(label_values) {
+            let mut label = prometheus::proto::LabelPair::default();
+            label.set_name(label_name.clone());
+            label.set_value(label_value);
+            m.mut_label().push(label);
+        }
+        mf.mut_metric().push(m);
+    }
+
+    Ok(mf)
+}
+
+#[derive(Debug)]
+struct MissingQueryParameter {
+    name: String,
+}
+
+#[derive(Debug)]
+enum ProbeError {
+    MissingParameter(String),
+    ModuleNotFound(String),
+    InvalidTargetUrl(warp::http::uri::InvalidUri),
+    TargetHTTP(hyper::Error),
+    TargetJSONParse(serde_json::Error),
+    TargetYAMLParse(serde_yaml::Error),
+    TargetJSONDepth(usize),
+    PrivateTarget(String),
+    Transform(String),
+    TargetTLS(String),
+    /// Reason the certificate of the target failed verification, with its
+    /// expiry if requested.
+    TargetTLSVerification(String, Option<f64>),
+    Credentials(String),
+    Redirect(String),
+    TooManyRedirects(usize),
+    Eval(String),
+    /// Failure of the last probe, which is reused within the minimum interval.
+    RateLimited(String),
+}
+
+impl warp::reject::Reject for MissingQueryParameter {}
+
+/// Returns the expiry of a certificate in DER as unix timestamp.
+fn certificate_expiry(der: &[u8]) -> Option<f64> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+    Some(cert.validity().not_after.timestamp() as f64)
+}
+
+/// Returns whether a request failed establishing its TLS session.
+fn is_tls_error(e: &hyper::Error) -> bool {
+    let mut source = std::error::Error::source(e);
+    while let Some(err) = source {
+        if err.is::<native_tls::Error>() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Classifies why a certificate presented for the host fails verification at
+/// the unix timestamp `now`. Certificates that look valid by themselves are
+/// issued by an untrusted CA.
+fn certificate_failure_reason(der: &[u8], host: &str, now: i64) -> &'static str {
+    let cert = match x509_parser::parse_x509_certificate(der) {
+        Ok((_, cert)) => cert,
+        Err(_) => return "other",
+    };
+    let validity = cert.validity();
+    if now < validity.not_before.timestamp() || now > validity.not_after.timestamp() {
+        "expired"
+    } else if !certificate_matches_host(&cert, host) {
+        "hostname_mismatch"
+    } else if cert.subject().to_string() == cert.issuer().to_string() {
+        "self_signed"
+    } else {
+        "untrusted"
+    }
+}
+
+/// Checks the host against the subject alternative names of a certificate.
+fn certificate_matches_host(cert: &x509_parser::certificate::X509Certificate, host: &str) -> bool {
+    use x509_parser::extensions::GeneralName;
+
+    let names = match cert.tbs_certificate.subject_alternative_name() {
+        Some((_, san)) => &san.general_names,
+        None => return false,
+    };
+    let ip: Option<std::net::IpAddr> = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok();
+    names.iter().any(|name| match (name, ip) {
+        (GeneralName::DNSName(pattern), None) => dns_name_matches(pattern, host),
+        (GeneralName::IPAddress(octets), Some(std::net::IpAddr::V4(ip))) => {
+            *octets == &ip.octets()[..]
+        }
+        (GeneralName::IPAddress(octets), Some(std::net::IpAddr::V6(ip))) => {
+            *octets == &ip.octets()[..]
+        }
+        _ => false,
+    })
+}
+
+/// Matches a host against a DNS name of a certificate, a leading wildcard
+/// matches a single label.
+fn dns_name_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let host = host.trim_end_matches('.').to_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => match host.find('.') {
+            Some(dot) => dot > 0 && host[dot + 1..] == *suffix,
+            None => false,
+        },
+        None => pattern == host,
+    }
+}
+
+/// Checks whether the address is loopback, private, link-local or otherwise
+/// not publicly routable.
+fn is_private_address(ip: &std::net::IpAddr) -> bool {
+    match ip {
+        std::net::IpAddr::V4(ip) => {
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // carrier-grade NAT, 100.64.0.0/10
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64)
+        }
+        std::net::IpAddr::V6(ip) => match ip.to_ipv4() {
+            // IPv4-mapped and -compatible addresses
+            Some(v4) if !ip.is_loopback() && !ip.is_unspecified() => {
+                is_private_address(&std::net::IpAddr::V4(v4))
+            }
+            _ => {
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    // unique local, fc00::/7
+                    || ip.segments()[0] & 0xfe00 == 0xfc00
+                    // link-local, fe80::/10
+                    || ip.segments()[0] & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+/// Checks whether arrays and objects of the JSON document nest deeper than
+/// `max_depth`, without parsing it.
+fn json_depth_exceeds(json: &[u8], max_depth: usize) -> bool {
+    JsonDepth::default().exceeds(json, max_depth)
+}
+
+/// Nesting of a JSON document, which is scanned in chunks.
+#[derive(Default)]
+struct JsonDepth {
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl JsonDepth {
+    /// Scans the next chunk of the document, returns whether its arrays and
+    /// objects nest deeper than `max_depth` so far.
+    fn exceeds(&mut self, json: &[u8], max_depth: usize) -> bool {
+        for c in json {
+            if self.in_string {
+                match c {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => self.in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match c {
+                b'"' => self.in_string = true,
+                b'[' | b'{' => {
+                    self.depth += 1;
+                    if self.depth > max_depth {
+                        return true;
+                    }
+                }
+                b']' | b'}' => self.depth = self.depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+        false
+    }
+}
+
+/// Looks up a probe parameter, the query parameter takes precedence over the
+/// header.
+fn probe_param(
+    params: &HashMap<String, String>,
+    headers: &HeaderMap,
+    name: &str,
+    header: &str,
+) -> std::result::Result<String, ProbeError> {
+    if let Some(value) = params.get(name) {
+        return Ok(value.clone());
+    }
+    match headers.get(header).map(|v| v.to_str()) {
+        Some(Ok(value)) => Ok(value.to_string()),
+        _ => Err(ProbeError::MissingParameter(name.into())),
+    }
+}
+
+impl warp::reject::Reject for ProbeError {}
+
+/// Returns true if both families share a name, but disagree on help or type
+/// or both contain the same series.
+fn families_conflict(a: &MetricFamily, b: &MetricFamily) -> bool {
+    a.get_name() == b.get_name()
+        && (a.get_help() != b.get_help()
+            || a.get_field_type() != b.get_field_type()
+            || duplicate_series(a, b).is_some())
+}
+
+/// Returns the labels of a series present in both families, regardless of
+/// the order of the labels.
+fn duplicate_series(a: &MetricFamily, b: &MetricFamily) -> Option<Vec<(String, String)>> {
+    let labels = |m: &prometheus::proto::Metric| {
+        let mut labels: Vec<(String, String)> = m
+            .get_label()
+            .iter()
+            .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+            .collect();
+        labels.sort();
+        labels
+    };
+    let series: Vec<_> = a.get_metric().iter().map(labels).collect();
+    b.get_metric()
+        .iter()
+        .map(labels)
+        .find(|labels| series.contains(labels))
+}
+
+/// Merges metric families with the same name into a single family.
+fn merge_families(families: Vec<MetricFamily>) -> Result<Vec<MetricFamily>> {
+    let mut merged: Vec<MetricFamily> = vec![];
+    for mut mf in families {
+        match merged.iter_mut().find(|m| m.get_name() == mf.get_name()) {
+            Some(existing) => {
+                if let Some(labels) = duplicate_series(existing, &mf) {
+                    return Err(format!(
+                        "duplicate series of metric '{}' with labels {:?}",
+                        mf.get_name(),
+                        labels
+                    )
+                    .into());
+                }
+                if families_conflict(existing, &mf) {
+                    return Err(format!(
+                        "conflicting definitions of metric '{}': help {:?} type {:?} != help {:?} type {:?}",
+                        mf.get_name(),
+                        existing.get_help(),
+                        existing.get_field_type(),
+                        mf.get_help(),
+                        mf.get_field_type(),
+                    )
+                    .into());
+                }
+                for m in mf.take_metric().into_iter() {
+                    existing.mut_metric().push(m);
+                }
+            }
+            None => merged.push(mf),
+        }
+    }
+    merged.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+    Ok(merged)
+}
+
+/// Builds the `probe_success` family, like the blackbox exporter does.
+fn probe_success_family(success: bool) -> MetricFamily {
+    gauge_family(
+        "probe_success",
+        "Displays whether or not the probe was a success",
+        &[],
+        if success { 1.0 } else { 0.0 },
+    )
+}
+
+/// Builds the `probe_ssl_earliest_cert_expiry` family, like the blackbox
+/// exporter does.
+fn cert_expiry_family(expiry: f64) -> MetricFamily {
+    gauge_family(
+        "probe_ssl_earliest_cert_expiry",
+        "Returns earliest SSL cert expiry in unixtime",
+        &[],
+        expiry,
+    )
+}
+
+/// Builds a single gauge sample family, used for the metrics the exporter
+/// adds to a probe.
+fn gauge_family(name: &str, help: &str, labels: &[(&str, &str)], value: f64) -> MetricFamily {
+    let mut gauge = prometheus::proto::Gauge::default();
+    gauge.set_value(value);
+
+    let mut metric = prometheus::proto::Metric::default();
+    metric.set_gauge(gauge);
+    metric.set_label(
+        labels
+            .iter()
+            .map(|(name, value)| {
+                let mut label = prometheus::proto::LabelPair::default();
+                label.set_name(name.to_string());
+                label.set_value(value.to_string());
+                label
+            })
+            .collect(),
+    );
+
+    let mut mf = MetricFamily::default();
+    mf.set_name(name.to_string());
+    mf.set_help(help.to_string());
+    mf.set_field_type(prometheus::proto::MetricType::GAUGE);
+    mf.set_metric(vec![metric].into());
+    mf
+}
+
+/// Adds labels to every metric of the families, labels the metric already
+/// carries are kept.
+fn add_labels(metric_families: &mut [MetricFamily], labels: &[(&str, &str)]) {
+    for mf in metric_families.iter_mut() {
+        for m in mf.mut_metric().iter_mut() {
+            for (name, value) in labels {
+                if m.get_label().iter().any(|l| l.get_name() == *name) {
+                    continue;
+                }
+                let mut label = prometheus::proto::LabelPair::default();
+                label.set_name(name.to_string());
+                label.set_value(value.to_string());
+                m.mut_label().push(label);
+            }
+            m.mut_label().sort_by(|a, b| a.get_name().cmp(b.get_name()));
+        }
+    }
+}
+
+/// Returns the `host:port` of a target, like Prometheus' `instance` label.
+fn target_instance(target: &str) -> Option<String> {
+    let uri: hyper::Uri = target.parse().ok()?;
+    let host = uri.host()?;
+    let port = match (uri.port_u16(), uri.scheme_str()) {
+        (Some(port), _) => port,
+        (None, Some("https")) => 443,
+        (None, _) => 80,
+    };
+    Some(format!("{}:{}", host, port))
+}
+
+/// Returns whether both URIs share scheme, host and port.
+fn same_origin(a: &hyper::Uri, b: &hyper::Uri) -> bool {
+    a.scheme_str() == b.scheme_str()
+        && target_instance(&a.to_string()) == target_instance(&b.to_string())
+}
+
+/// Orders the labels of every metric by their position in `label_order`,
+/// followed by the remaining labels sorted by name.
+fn sort_labels(metric_families: &mut [MetricFamily], label_order: &[String]) {
+    let position = |name: &str| {
+        label_order
+            .iter()
+            .position(|l| l == name)
+            .unwrap_or(label_order.len())
+    };
+    for mf in metric_families.iter_mut() {
+        for m in mf.mut_metric().iter_mut() {
+            m.mut_label().sort_by(|a, b| {
+                position(a.get_name())
+                    .cmp(&position(b.get_name()))
+                    .then_with(|| a.get_name().cmp(b.get_name()))
+            });
+        }
+    }
+}
+
+/// Compresses the body with gzip, when the client accepts it.
+fn compress(
+    response: warp::http::response::Builder,
+    accept_encoding: Option<&str>,
+    body: Vec<u8>,
+) -> (warp::http::response::Builder, Vec<u8>) {
+    let response = response.header(VARY, "Accept-Encoding");
+
+    let gzip = match accept_encoding {
+        Some(accept_encoding) => accept_encoding
+            .split(',')
+            .any(|encoding| encoding.split(';').next().map(str::trim) == Some("gzip")),
+        None => false,
+    };
+    if !gzip {
+        return (response, body);
+    }
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    match encoder.write_all(&body).and_then(|_| encoder.finish()) {
+        Ok(compressed) => (response.header(CONTENT_ENCODING, "gzip"), compressed),
+        Err(e) => {
+            error!("cannot compress response: {}", e);
+            (response, body)
+        }
+    }
+}
+
+/// Encodes a single family in the text format, preceded by a comment naming
+/// the modules it originates from, if given.
+fn encode_family(mf: &MetricFamily, modules: Option<&Vec<String>>) -> Vec<u8> {
+    let mut buffer = vec![];
+    if let Some(modules) = modules {
+        writeln!(buffer, "# module: {}", modules.join(", ")).unwrap();
+    }
+    prometheus::TextEncoder::new()
+        .encode(std::slice::from_ref(mf), &mut buffer)
+        .unwrap();
+    buffer
+}
+
+fn encode_families(metric_families: &[MetricFamily]) -> Result<String> {
+    // exemplars are not supported by the text format
+    let metric_families: Vec<MetricFamily> = metric_families
+        .iter()
+        .filter(|mf| !openmetrics::is_exemplar_family(mf.get_name()))
+        .cloned()
+        .collect();
+
+    let mut buffer = vec![];
+    let encoder = prometheus::TextEncoder::new();
+    encoder.encode(&metric_families, &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+/// Manifest of a module, which isn't a valid set of metrics.
+#[derive(Debug)]
+struct ManifestError {
+    source: serde_json::Error,
+    manifest: String,
+}
+
+impl std::fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid metrics in manifest: {}", self.source)
+    }
+}
+
+impl Error for ManifestError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// External variables bound for every evaluation of a module.
+const EXT_VARS: &[&str] = &["input"];
+
+struct Module {
+    path: Rc<PathBuf>,
+    state: EvaluationState,
+    meta_metrics: bool,
+    omit_missing_help: bool,
+    label_cardinality: bool,
+    allowed_metrics: Option<Vec<String>>,
+    clock: Clock,
+}
+
+/// Metric families of a single evaluation, before the metrics describing the
+/// evaluation are added.
+#[derive(Default)]
+struct Evaluation {
+    /// Families of the metrics emitted by the module.
+    families: Vec<MetricFamily>,
+    /// Creation times of counters, see `openmetrics::created_name`.
+    created_families: Vec<MetricFamily>,
+    exemplar_families: Vec<MetricFamily>,
+    /// Number of series emitted by the module, before duplicates collapsed.
+    samples_scraped: usize,
+}
+
+impl Evaluation {
+    /// Adds the families of another evaluation. Series with the same labels
+    /// collapse like within an evaluation: the values of counters are summed,
+    /// series of other metrics are replaced.
+    fn merge(&mut self, other: Evaluation) -> Result<()> {
+        merge_series(&mut self.families, other.families)?;
+        merge_series(&mut self.created_families, other.created_families)?;
+        merge_series(&mut self.exemplar_families, other.exemplar_families)?;
+        self.samples_scraped += other.samples_scraped;
+        Ok(())
+    }
+}
+
+/// Merges families into the families with the same name, see
+/// `Evaluation::merge`.
+fn merge_series(into: &mut Vec<MetricFamily>, families: Vec<MetricFamily>) -> Result<()> {
+    for mut mf in families {
+        let existing = match into.iter_mut().find(|m| m.get_name() == mf.get_name()) {
+            Some(existing) => existing,
+            None => {
+                into.push(mf);
+                continue;
+            }
+        };
+        if families_conflict(existing, &mf) {
+            return Err(format!("conflicting definitions of metric '{}'", mf.get_name()).into());
+        }
+
+        let counter = existing.get_field_type() == prometheus::proto::MetricType::COUNTER;
+        for m in mf.take_metric().into_iter() {
+            match existing
+                .mut_metric()
+                .iter_mut()
+                .find(|e| e.get_label() == m.get_label())
+            {
+                Some(e) if counter => {
+                    let value = e.get_counter().get_value() + m.get_counter().get_value();
+                    e.mut_counter().set_value(value);
+                }
+                Some(e) => *e = m,
+                None => existing.mut_metric().push(m),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Evaluates the module for every element of a JSON array, while it is being
+/// parsed.
+struct StreamVisitor<'a> {
+    module: &'a Module,
+    max_elements: usize,
+    evaluation: &'a mut Evaluation,
+}
+
+impl<'de, 'a> serde::de::Visitor<'de> for StreamVisitor<'a> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a JSON array")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> std::result::Result<(), A::Error>
+    where
+        A: serde::de::SeqAccess<'de>,
+    {
+        use serde::de::Error;
+
+        let mut count = 0;
+        while let Some(element) = seq.next_element::<serde_json::Value>()? {
+            count += 1;
+            if count > self.max_elements {
+                return Err(A::Error::custom(format!(
+                    "array has more than {} elements",
+                    self.max_elements
+                )));
+            }
+
+            let evaluation = self
+                .module
+                .evaluate(&serde_json::json!({ "body": element }))
+                .map_err(A::Error::custom)?;
+            self.evaluation
+                .merge(evaluation)
+                .map_err(A::Error::custom)?;
+        }
+        Ok(())
+    }
+}
+
+impl Module {
+    /// Evaluates every element of the JSON array read from the reader as
+    /// body of its own input, the series of all elements are merged.
+    fn eval_stream<R: Read>(&self, reader: R, max_elements: usize) -> Result<Vec<MetricFamily>> {
+        let mut evaluation = Evaluation::default();
+        let mut de = serde_json::Deserializer::from_reader(reader);
+        serde::Deserializer::deserialize_seq(
+            &mut de,
+            StreamVisitor {
+                module: self,
+                max_elements,
+                evaluation: &mut evaluation,
+            },
+        )?;
+        de.end()?;
+        Ok(self.finish(evaluation))
+    }
+
+    /// Renders an evaluation error, references of undefined ext vars are
+    /// reported together with the available ones.
+    fn eval_error(&self, e: &LocError) -> String {
+        match e.error() {
+            jrsonnet_evaluator::error::Error::UndefinedExternalVariable(name) => format!(
+                "module referenced undefined ext var '{}'; available: [{}]",
+                name,
+                EXT_VARS.join(", ")
+            ),
+            _ => self.state.stringify_err(e),
+        }
+    }
+
+    /// Evaluates the input of a test, which may use jsonnet syntax beyond
+    /// plain JSON, into the value passed to the module.
+    fn eval_input(&self, code: &str) -> Result<serde_json::Value> {
+        let path = Rc::new(PathBuf::from("input.jsonnet"));
+        let result = self
+            .state
+            .evaluate_snippet_raw(path, code.into())
+            .map_err(|e| self.state.stringify_err(&e))?;
+        let manifest = self
+            .state
+            .manifest(result)
+            .map_err(|e| self.state.stringify_err(&e))?;
+        Ok(serde_json::from_str(&manifest)?)
+    }
+
+    /// Evaluates the module and adds the metrics describing the evaluation.
+    fn eval_families(&self, input: &serde_json::Value) -> Result<Vec<MetricFamily>> {
+        Ok(self.finish(self.evaluate(input)?))
+    }
+
+    /// Evaluates the module, the input is bound as jsonnet value, so it is
+    /// not serialized and parsed again.
+    fn evaluate(&self, input: &serde_json::Value) -> Result<Evaluation> {
+        let eval = format!(
+            r#"
+local s = import '{}';
+
+s.process(std.extVar("input"))
+"#,
+            self.path.to_path_buf().to_str().expect("unpack string")
+        );
+
+        self.state.add_ext_var(EXT_VARS[0].into(), Val::from(input));
+
+        let path = Rc::new(PathBuf::from("eval.jsonnet"));
+        let result = self
+            .state
+            .evaluate_snippet_raw(path, eval.into())
+            .map_err(|e| self.eval_error(&e))?;
+        info!("result = {:?}", result);
+
+        let manifest = self
+            .state
+            .manifest(result)
+            .map_err(|e| self.eval_error(&e))?;
+
+        let metrics: Metrics = serde_json::from_str(&manifest).map_err(|e| ManifestError {
+            source: e,
+            manifest: manifest.to_string(),
+        })?;
+
+        if let Some(allowed_metrics) = &self.allowed_metrics {
+            for name in metrics.0.keys() {
+                if !allowed_metrics.contains(name) {
+                    return Err(format!("metric '{}' is not in allowed_metrics", name).into());
+                }
+            }
+        }
+
+        let registry = prometheus::Registry::new();
+
+        // Count every sample the module emitted, before duplicates collapse
+        let samples_scraped: usize = metrics.0.values().map(|m| m.series.len()).sum();
+
+        let mut extra_families = vec![];
+        let mut created_families = vec![];
+        let mut exemplar_families = vec![];
+
+        for (metric_name, metric) in metrics.0 {
+            let metric_name = NameEscaping::Prometheus.escape(&metric_name);
+            exemplar_families.extend(exemplar_family(&metric_name, &metric)?);
+
+            let label_names: Vec<&str> = match &metric.label_names {
+                Some(ln) => ln.iter().map(std::ops::Deref::deref).collect(),
+                None => vec![],
+            };
+            let opts = prometheus::Opts::new(
+                metric_name.clone(),
+                match &metric.help {
+                    Some(help) => help,
+                    _ => MISSING_HELP,
+                },
+            );
+            match metric.r#type {
+                MetricType::Gauge => {
+                    let m = prometheus::GaugeVec::new(opts, &label_names)?;
+                    registry.register(Box::new(m.clone()))?;
+
+                    for s in &metric.series {
+                        m.with_label_values(&s.label_values())
+                            .set(s.value(&metric_name)?);
+                    }
+                }
+                MetricType::Counter => {
+                    let m = prometheus::CounterVec::new(opts, &label_names)?;
+                    registry.register(Box::new(m.clone()))?;
+
+                    // creation times known from the module, the remaining
+                    // ones are tracked across probes
+                    let mut created = gauge_family(
+                        &openmetrics::created_name(&metric_name),
+                        &format!("Creation time of {}.", metric_name),
+                        &[],
+                        0.0,
+                    );
+                    created.mut_metric().clear();
+
+                    for s in &metric.series {
+                        let value = s.value(&metric_name)?;
+                        if value < 0.0 {
+                            return Err(
+                                format!("counter '{}' has negative value", metric_name).into()
+                            );
+                        }
+                        m.with_label_values(&s.label_values()).inc_by(value);
+
+                        let created_at = match (s.created_ms, s.reset) {
+                            (Some(created_ms), _) => created_ms / 1000.0,
+                            (None, true) => self.clock.now(),
+                            (None, false) => continue,
+                        };
+                        let labels: Vec<(&str, &str)> =
+                            label_names.iter().copied().zip(s.label_values()).collect();
+                        let mut created_family =
+                            gauge_family(created.get_name(), "", &labels, created_at);
+                        created
+                            .mut_metric()
+                            .push(created_family.take_metric().remove(0));
+                    }
+
+                    if !created.get_metric().is_empty() {
+                        created_families.push(created);
+                    }
+                }
+                MetricType::Histogram => {
+                    extra_families.push(histogram_family(&metric_name, &metric)?);
+                }
+                MetricType::Untyped => {
+                    // set like a gauge, then exposed without a type
+                    let m = prometheus::GaugeVec::new(opts, &label_names)?;
+                    for s in &metric.series {
+                        m.with_label_values(&s.label_values())
+                            .set(s.value(&metric_name)?);
+                    }
+
+                    for mut mf in m.collect() {
+                        mf.set_field_type(prometheus::proto::MetricType::UNTYPED);
+                        for m in mf.mut_metric().iter_mut() {
+                            let mut untyped = prometheus::proto::Untyped::default();
+                            untyped.set_value(m.take_gauge().get_value());
+                            m.set_untyped(untyped);
+                        }
+                        extra_families.push(mf);
+                    }
+                }
+            };
+        }
+
+        // Gather the metrics.
+        let mut families = registry.gather();
+        families.extend(extra_families);
+
+        Ok(Evaluation {
+            families,
+            created_families,
+            exemplar_families,
+            samples_scraped,
+        })
+    }
+
+    /// Adds the metrics describing an evaluation to its families.
+    fn finish(&self, evaluation: Evaluation) -> Vec<MetricFamily> {
+        let mut metric_families = evaluation.families;
+
+        // Count the samples of the module's own metrics, after duplicates
+        // collapsed
+        let samples_post_filtering: usize =
+            metric_families.iter().map(|mf| mf.get_metric().len()).sum();
+        metric_families.extend(evaluation.created_families);
+
+        if self.omit_missing_help {
+            // the encoder skips the HELP line of families without help
+            for mf in metric_families.iter_mut() {
+                if mf.get_help() == MISSING_HELP {
+                    mf.clear_help();
+                }
+            }
+        }
+
+        if self.label_cardinality {
+            let mut values: HashMap<&str, std::collections::HashSet<&str>> = HashMap::new();
+            for mf in &metric_families {
+                for m in mf.get_metric() {
+                    for label in m.get_label() {
+                        values
+                            .entry(label.get_name())
+                            .or_default()
+                            .insert(label.get_value());
+                    }
+                }
+            }
+
+            let mut cardinality = gauge_family(
+                "probe_label_cardinality",
+                "Number of distinct values of the label in the last probe.",
+                &[],
+                0.0,
+            );
+            cardinality.mut_metric().clear();
+            let mut labels: Vec<(&str, usize)> =
+                values.iter().map(|(label, v)| (*label, v.len())).collect();
+            labels.sort();
+            for (label, count) in labels {
+                let mut family = gauge_family("", "", &[("label", label)], count as f64);
+                cardinality
+                    .mut_metric()
+                    .push(family.take_metric().remove(0));
+            }
+            metric_families.push(cardinality);
+        }
+
+        if self.meta_metrics {
+            metric_families.push(gauge_family(
+                "probe_samples_scraped",
+                "Number of samples the module emitted.",
+                &[],
+                evaluation.samples_scraped as f64,
+            ));
+            metric_families.push(gauge_family(
+                "probe_samples_post_filtering",
+                "Number of samples remaining after duplicate series have been dropped.",
+                &[],
+                samples_post_filtering as f64,
+            ));
+        }
+
+        metric_families.extend(evaluation.exemplar_families);
+        metric_families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+
+        metric_families
+    }
+}
+
+/// Result of the last fetch of a rate limited target.
+struct LastProbe {
+    fetched_at: Instant,
+    /// Metric families or the error of the probe.
+    result: std::result::Result<Vec<MetricFamily>, String>,
+}
+
+/// Seconds after which the counter creation times of a target, which hasn't
+/// been probed since, are forgotten.
+const COUNTERS_CREATED_TTL: f64 = 3600.0;
+
+/// Creation times of the counter series of a target, by series.
+struct CountersCreated {
+    probed_at: f64,
+    series: HashMap<String, f64>,
+}
+
+/// Reads and parses the config file, relative paths of modules are resolved
+/// against `--base-dir` or the directory of the config file. Returns the
+/// config with the hex digest of the file.
+fn load_config(opts: &Opts) -> Result<(Config, String)> {
+    let config_file =
+        std::fs::read(&opts.config_file).map_err(|e| format!("cannot open config file: {}", e))?;
+    let config_sha256 = format!("{:x}", Sha256::digest(&config_file));
+    let mut config: Config = serde_yaml::from_slice(&config_file)
+        .map_err(|e| format!("cannot parse config file: {}", e))?;
+    config.base_dir = match &opts.base_dir {
+        Some(base_dir) => base_dir.clone(),
+        None => Path::new(&opts.config_file)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default(),
+    };
+    config.jpath = opts.jpath.clone();
+    if let Some(jsonnet_path) = std::env::var_os("JSONNET_PATH") {
+        config.jpath.extend(std::env::split_paths(&jsonnet_path));
+    }
+    Ok((config, config_sha256))
+}
+
+struct App {
+    config: Config,
+    /// Hex digest of the config file as it was loaded.
+    config_sha256: String,
+    opts: Opts,
+
+    /// Registry of the exporter's own metrics, prefixed with
+    /// `--metrics-namespace`.
+    registry: prometheus::Registry,
+
+    /// Metric families of the last successful background scrape per module.
+    background_families: Mutex<HashMap<String, Vec<MetricFamily>>>,
+
+    /// Last evaluation error per module, exposed on `/debug/last-error`.
+    last_errors: Mutex<HashMap<String, String>>,
+
+    /// Most recent probe results per module and target, included in
+    /// `/metrics` when enabled.
+    probe_families: Mutex<Vec<((String, String), Vec<MetricFamily>)>>,
+
+    /// Creation time of counter series per module and target.
+    counters_created: Mutex<HashMap<(String, String), CountersCreated>>,
+
+    /// Last probe per module and target, used to rate limit fetches. The
+    /// lock of a target is held while it is fetched, entries are evicted
+    /// once the minimum interval of their module passed.
+    last_probes: Mutex<HashMap<(String, String), Arc<tokio::sync::Mutex<Option<LastProbe>>>>>,
+
+    /// Credentials per file together with the time they were read.
+    credentials: Mutex<HashMap<PathBuf, (Instant, String)>>,
+
+    /// Most recent evaluation results per module, keyed by the hash of their
+    /// input.
+    eval_cache: Mutex<HashMap<String, Vec<(String, Vec<MetricFamily>)>>>,
+
+    /// Parsed jsonnet of the modules and their imports.
+    parsed: ParseCache,
+}
+
+impl App {
+    fn new() -> Self {
+        let opts: Opts = Opts::parse();
+
+        // Setup logger with default level info so we can see the messages from
+        // prometheus_exporter.
+        Builder::from_env(Env::default().default_filter_or("info")).init();
+
+        let (config, config_sha256) = load_config(&opts).expect("cannot load config file");
+        debug!("read config {:?}", config);
+
+        App::with_config(opts, config, config_sha256)
+    }
+
+    /// Builds the app from already parsed options and config.
+    fn with_config(opts: Opts, config: Config, config_sha256: String) -> Self {
+        let registry = self_metrics_registry(&opts.metrics_namespace);
+        if opts.config_checksum_metric {
+            let checksum = Gauge::with_opts(
+                prometheus::Opts::new(
+                    "config_file_sha256",
+                    "SHA256 checksum of the loaded config file.",
+                )
+                .const_label("sha256", config_sha256.as_str()),
+            )
+            .unwrap();
+            checksum.set(1.0);
+            registry.register(Box::new(checksum)).unwrap();
+        }
+
+        App {
+            registry,
+            config,
+            config_sha256,
+            opts,
+            background_families: Mutex::new(HashMap::new()),
+            last_errors: Mutex::new(HashMap::new()),
+            probe_families: Mutex::new(vec![]),
+            counters_created: Mutex::new(HashMap::new()),
+            last_probes: Mutex::new(HashMap::new()),
+            credentials: Mutex::new(HashMap::new()),
+            eval_cache: Mutex::new(HashMap::new()),
+            parsed: ParseCache::default(),
+        }
+    }
+
+    /// Returns the background scraped metric families of all modules in the
+    /// given exposition group, together with the name of their module.
+    fn group_families(&self, group: Option<&str>) -> Vec<(String, MetricFamily)> {
+        let background_families = self.background_families.lock().unwrap();
+        let mut families: Vec<(String, MetricFamily)> = vec![];
+
+        // sort modules, so namespacing of conflicts is stable
+        let mut modules: Vec<(&String, &ConfigModule)> = self.config.modules.iter().collect();
+        modules.sort_by_key(|(name, _)| *name);
+
+        for (name, module) in modules {
+            let module_group = match &module.background {
+                Some(background) => background.group.as_deref(),
+                None => continue,
+            };
+            if module_group != group {
+                continue;
+            }
+            if let Some(mfs) = background_families.get(name) {
+                for mf in mfs {
+                    let mut mf = mf.clone();
+                    if self.config.duplicate_metrics == DuplicateMetrics::Namespace
+                        && families.iter().any(|(_, f)| families_conflict(f, &mf))
+                    {
+                        let namespaced = format!("{}_{}", name, mf.get_name());
+                        mf.set_name(namespaced);
+                    }
+                    families.push((name.clone(), mf));
+                }
+            }
+        }
+        families
+    }
+
+    fn has_group(&self, group: &str) -> bool {
+        self.config.modules.values().any(|m| match &m.background {
+            Some(background) => background.group.as_deref() == Some(group),
+            None => false,
+        })
+    }
+
+    /// Returns the metrics of a single module, from its background scrape or
+    /// by probing its configured targets.
+    async fn module_metrics(
+        &self,
+        name: &str,
+        accept_encoding: Option<String>,
+    ) -> std::result::Result<Response<hyper::Body>, Rejection> {
+        let module = match self.config.modules.get(name) {
+            Some(module) if self.opts.metrics_per_module => module,
+            _ => return Err(warp::reject::not_found()),
+        };
+
+        let metric_families = if module.background.is_some() {
+            self.background_families
+                .lock()
+                .unwrap()
+                .get(name)
+                .cloned()
+                .unwrap_or_default()
+        } else {
+            self.probe_targets(name, module, None)
+                .await
+                .map_err(warp::reject::custom)?
+        };
+        let mut metric_families = merge_families(metric_families)
+            .map_err(|e| warp::reject::custom(ProbeError::Eval(e.to_string())))?;
+        sort_labels(&mut metric_families, &self.config.label_order);
+
+        let body = encode_families(&metric_families).unwrap().into_bytes();
+        let (response, body) = compress(
+            Response::builder().header(CACHE_CONTROL, "no-cache"),
+            accept_encoding.as_deref(),
+            body,
+        );
+        Ok(response
+            .header(CONTENT_TYPE, TextEncoder::new().format_type())
+            .header(CONTENT_LENGTH, body.len())
+            .body(body.into())
+            .unwrap())
+    }
+
+    /// Serves the metrics of the exposition group, or of all ungrouped
+    /// modules together with the exporter's own metrics. Matching
+    /// `If-None-Match` requests are answered with 304, which only happens for
+    /// groups, as the own metrics count every request.
+    async fn metrics_handler(
+        &self,
+        group: Option<String>,
+        if_none_match: Option<String>,
+        accept_encoding: Option<String>,
+    ) -> std::result::Result<impl Reply, Rejection> {
+        let encoder = TextEncoder::new();
+
+        HTTP_COUNTER.inc();
+        let timer = HTTP_REQ_HISTOGRAM.with_label_values(&["all"]).start_timer();
+
+        let mut metric_families = vec![];
+        match &group {
+            Some(name) if !self.has_group(name) => {
+                return self.module_metrics(name, accept_encoding).await;
+            }
+            Some(_) => {}
+            None => {
+                metric_families.extend(prometheus::gather());
+                metric_families.extend(self.registry.gather());
+            }
+        };
+
+        // modules a metric family originates from
+        let mut sources: HashMap<String, Vec<String>> = HashMap::new();
+        for (module, mf) in self.group_families(group.as_deref()) {
+            sources
+                .entry(mf.get_name().to_string())
+                .or_default()
+                .push(module);
+            metric_families.push(mf);
+        }
+
+        if group.is_none() {
+            // stored probe results must not fail the exposition, conflicting
+            // families are prefixed with their module or skipped
+            for ((module, _), mfs) in self.probe_families.lock().unwrap().iter() {
+                for mf in mfs {
+                    let mut mf = mf.clone();
+                    if metric_families.iter().any(|f| families_conflict(f, &mf)) {
+                        mf.set_name(format!("{}_{}", module, mf.get_name()));
+                    }
+                    if metric_families.iter().any(|f| families_conflict(f, &mf)) {
+                        debug!("skipping conflicting probe metric '{}'", mf.get_name());
+                        continue;
+                    }
+                    metric_families.push(mf);
+                }
+            }
+        }
+
+        let mut metric_families = match merge_families(metric_families) {
+            Ok(metric_families) => metric_families,
+            Err(e) => {
+                error!("cannot merge metrics: {}", e);
+                return Ok(Response::builder()
+                    .status(500)
+                    .body(format!("cannot merge metrics: {}", e).into())
+                    .unwrap());
+            }
+        };
+
+        sort_labels(&mut metric_families, &self.config.label_order);
+
+        // exemplars are not supported by the text format
+        metric_families.retain(|mf| !openmetrics::is_exemplar_family(mf.get_name()));
+        if !self.opts.debug {
+            sources.clear();
+        }
+
+        if self.opts.stream_metrics {
+            // families are encoded one at a time, while the body is sent, the
+            // response is recorded once the last one has been sent
+            let mut metric_families = metric_families.into_iter();
+            let mut size = 0;
+            let mut timer = Some(timer);
+            let chunks = std::iter::from_fn(move || match metric_families.next() {
+                Some(mf) => {
+                    let chunk = encode_family(&mf, sources.get(mf.get_name()));
+                    size += chunk.len();
+                    Some(Ok::<_, std::io::Error>(chunk))
+                }
+                None => {
+                    if let Some(timer) = timer.take() {
+                        HTTP_BODY_GAUGE.set(size as f64);
+                        timer.observe_duration();
+                    }
+                    None
+                }
+            });
+            return Ok(Response::builder()
+                .status(200)
+                .header(CACHE_CONTROL, "no-cache")
+                .header(CONTENT_TYPE, encoder.format_type())
+                .body(hyper::Body::wrap_stream(futures::stream::iter(chunks)))
+                .unwrap());
+        }
+
+        let mut buffer = vec![];
+        for mf in &metric_families {
+            buffer.extend(encode_family(mf, sources.get(mf.get_name())));
+        }
+        HTTP_BODY_GAUGE.set(buffer.len() as f64);
+
+        // the tag is weak, as it is shared by the plain and the compressed
+        // body, it is compared without its weakness indicator
+        let etag = format!("W/\"{:x}\"", Sha256::digest(&buffer));
+        let not_modified = match &if_none_match {
+            Some(if_none_match) => if_none_match.split(',').any(|tag| {
+                let tag = tag.trim();
+                tag == "*" || tag.trim_start_matches("W/") == etag.trim_start_matches("W/")
+            }),
+            None => false,
+        };
+
+        let response = Response::builder()
+            .header(CACHE_CONTROL, "no-cache")
+            .header(ETAG, &etag);
+        let response = if not_modified {
+            response
+                .status(304)
+                .header(VARY, "Accept-Encoding")
+                .body(hyper::Body::empty())
+        } else {
+            let (response, buffer) = compress(response, accept_encoding.as_deref(), buffer);
+            response
+                .status(200)
+                .header(CONTENT_TYPE, encoder.format_type())
+                .header(CONTENT_LENGTH, buffer.len())
+                .body(buffer.into())
+        }
+        .unwrap();
+
+        timer.observe_duration();
+
+        Ok(response)
+    }
+
+    fn clock(&self) -> Clock {
+        match self.opts.clock {
+            Some(clock) => Clock::Fixed(clock),
+            None => Clock::System,
+        }
+    }
+
+    fn bench(&self, bench: &Bench) -> Result<String> {
+        let module = match self.config.modules.get(&bench.module) {
+            Some(module) => module,
+            None => return Err(format!("module '{}' not found", bench.module).into()),
+        };
+        if bench.iterations == 0 {
+            return Err("iterations has to be at least 1".into());
+        }
+
+        let body: serde_json::Value =
+            serde_json::from_reader(BufReader::new(File::open(&bench.input)?))?;
+        let data = serde_json::json!({ "body": body });
+        let module = module.state(
+            &self.config.base_dir,
+            &self.config.jpath,
+            &self.parsed,
+            self.clock(),
+        )?;
+
+        let mut durations = Vec::with_capacity(bench.iterations);
+        #[cfg(feature = "count-allocations")]
+        let allocations = allocations::count();
+        for _ in 0..bench.iterations {
+            let start = Instant::now();
+            encode_families(&module.eval_families(&data)?)?;
+            durations.push(start.elapsed());
+        }
+
+        let total: Duration = durations.iter().sum();
+        let report = format!(
+            "iterations:  {}\nmin:         {:?}\navg:         {:?}\nmax:         {:?}\n",
+            bench.iterations,
+            durations.iter().min().unwrap(),
+            total / bench.iterations as u32,
+            durations.iter().max().unwrap(),
+        );
+        #[cfg(feature = "count-allocations")]
+        let report = {
+            let allocations = allocations::count() - allocations;
+            format!(
+                "{}allocations: {} ({} per iteration)\n",
+                report,
+                allocations,
+                allocations / bench.iterations
+            )
+        };
+
+        Ok(report)
+    }
+
+    /// Runs the tests of all modules on a pool of threads, returns whether all
+    /// of them passed.
+    fn test(&'static self, test: &Test) -> bool {
+        let mut passed = true;
+        for (name, results) in self.test_reports(test.concurrency) {
+            match results {
+                Ok(results) => {
+                    for result in results {
+                        match result.diff {
+                            None => println!("ok   {} #{}", name, result.test),
+                            Some(diff) => {
+                                passed = false;
+                                println!("FAIL {} #{}\n{}", name, result.test, diff);
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    passed = false;
+                    println!("FAIL {}: {}", name, e);
+                }
+            }
+        }
+        passed
+    }
+
+    /// Returns the test results of all modules sorted by module, tested on
+    /// the given number of threads.
+    fn test_reports(
+        &'static self,
+        concurrency: usize,
+    ) -> Vec<(
+        &'static String,
+        std::result::Result<Vec<TestResult>, String>,
+    )> {
+        let mut modules: Vec<(&'static String, &'static ConfigModule)> =
+            self.config.modules.iter().collect();
+        modules.sort_by_key(|(name, _)| *name);
+        let queue = std::sync::Arc::new(Mutex::new(modules.into_iter()));
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let workers: Vec<_> = (0..concurrency.max(1))
+            .map(|_| {
+                let queue = queue.clone();
+                let tx = tx.clone();
+                std::thread::spawn(move || loop {
+                    let next = queue.lock().unwrap().next();
+                    let (name, module) = match next {
+                        Some(next) => next,
+                        None => break,
+                    };
+                    let results = module
+                        .run_tests(&self.config.base_dir, &self.config.jpath, &self.parsed)
+                        .map_err(|e| e.to_string());
+                    tx.send((name, results)).unwrap();
+                })
+            })
+            .collect();
+        drop(tx);
+
+        let mut reports: Vec<_> = rx.iter().collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        reports.sort_by_key(|(name, _)| *name);
+        reports
+    }
+
+    fn eval(&self, eval: &Eval) -> Result<String> {
+        let module = match self.config.modules.get(&eval.module) {
+            Some(module) => module,
+            None => return Err(format!("module '{}' not found", eval.module).into()),
+        };
+
+        let input = std::fs::read(&eval.input)?;
+        let body = module
+            .parse_body(eval.content_type, &input)
+            .map_err(|e| format!("{:?}", e))?;
+        let body = transforms::apply(&module.transforms, body)?;
+
+        let metric_families = module
+            .state(
+                &self.config.base_dir,
+                &self.config.jpath,
+                &self.parsed,
+                self.clock(),
+            )?
+            .eval_families(&serde_json::json!({ "body": body }))?;
+        encode_families(&metric_families)
+    }
+
+    async fn run_once(&self, run_once: &RunOnce) -> Result<String> {
+        let module = match self.config.modules.get(&run_once.module) {
+            Some(module) => module,
+            None => return Err(format!("module '{}' not found", run_once.module).into()),
+        };
+
+        let mut module = module.clone();
+        if run_once.content_type.is_some() {
+            module.content_type = run_once.content_type;
+        }
+
+        let metric_families = self
+            .probe_targets(&run_once.module, &module, run_once.target.clone())
+            .await
+            .map_err(|e| format!("probe failed: {:?}", e))?;
+        let mut metric_families = merge_families(metric_families)?;
+        sort_labels(&mut metric_families, &self.config.label_order);
+
+        encode_families(&metric_families)
+    }
+
+    fn eval_module(
+        &self,
+        name: &str,
+        module: &ConfigModule,
+        fetched: &mut Fetched,
+    ) -> std::result::Result<Vec<MetricFamily>, ProbeError> {
+        // streamed bodies are evaluated while they are received, so their
+        // hash isn't known upfront
+        let cache_key = match (module.eval_cache_size, &fetched.stream) {
+            (Some(_), None) => {
+                let mut hasher = Sha256::new();
+                hasher.update(fetched.data.to_string());
+                Some(format!("{:x}", hasher.finalize()))
+            }
+            _ => None,
+        };
+        if let Some(key) = &cache_key {
+            if let Some(entries) = self.eval_cache.lock().unwrap().get(name) {
+                if let Some((_, metric_families)) = entries.iter().find(|(k, _)| k == key) {
+                    debug!("module '{}' input unchanged, reusing evaluation", name);
+                    return Ok(metric_families.clone());
+                }
+            }
+        }
+
+        EVALUATIONS.with_label_values(&[name]).inc();
+        let result = module
+            .state(
+                &self.config.base_dir,
+                &self.config.jpath,
+                &self.parsed,
+                self.clock(),
+            )
+            .and_then(
+                |m| match (fetched.stream.take(), module.stream_max_elements) {
+                    (Some(body), Some(max_elements)) => {
+                        let mut reader = BodyReader::new(body, module.max_json_depth);
+                        let result = tokio::task::block_in_place(|| {
+                            m.eval_stream(&mut reader, max_elements)
+                        });
+                        fetched.body_size = reader.size;
+                        result
+                    }
+                    _ => m.eval_families(&fetched.data),
+                },
+            );
+        match result {
+            Ok(mut metric_families) => {
+                for mf in metric_families.iter_mut() {
+                    if mf.get_name() == "probe_label_cardinality" {
+                        add_labels(std::slice::from_mut(mf), &[("module", name)]);
+                    }
+                }
+
+                if let (Some(key), Some(size)) = (cache_key, module.eval_cache_size) {
+                    let mut eval_cache = self.eval_cache.lock().unwrap();
+                    let entries = eval_cache.entry(name.to_string()).or_default();
+                    entries.push((key, metric_families.clone()));
+                    if entries.len() > size {
+                        entries.remove(0);
+                    }
+                }
+                Ok(metric_families)
+            }
+            Err(e) => {
+                let e = match e.downcast_ref::<ManifestError>() {
+                    // the manifest may contain data of the target
+                    Some(manifest_error) if self.opts.debug => {
+                        format!("{}\nmanifest:\n{}", manifest_error, manifest_error.manifest)
+                    }
+                    _ => e.to_string(),
+                };
+                if self.opts.debug {
+                    self.last_errors
+                        .lock()
+                        .unwrap()
+                        .insert(name.to_string(), e.clone());
+                }
+                Err(ProbeError::Eval(e))
+            }
+        }
+    }
+
+    fn module_infos(&self) -> Vec<ModuleInfo> {
+        let mut modules: Vec<ModuleInfo> = self
+            .config
+            .modules
+            .iter()
+            .map(|(name, module)| ModuleInfo {
+                name: name.clone(),
+                description: module.description.clone(),
+            })
+            .collect();
+        modules.sort_by(|a, b| a.name.cmp(&b.name));
+        modules
+    }
+
+    async fn modules_handler(&self) -> std::result::Result<impl Reply, Rejection> {
+        Ok(warp::reply::json(&self.module_infos()))
+    }
+
+    /// Runs the tests of all modules, on a blocking thread as evaluations may
+    /// take a while. Only available in debug mode, as it is expensive.
+    async fn tests_handler(&'static self) -> std::result::Result<impl Reply, Rejection> {
+        if !self.opts.debug {
+            return Err(warp::reject::not_found());
+        }
+
+        let reports = tokio::task::spawn_blocking(move || {
+            let mut modules: Vec<(&String, &ConfigModule)> = self.config.modules.iter().collect();
+            modules.sort_by_key(|(name, _)| *name);
+
+            modules
+                .into_iter()
+                .map(|(name, module)| {
+                    match module.run_tests(&self.config.base_dir, &self.config.jpath, &self.parsed)
+                    {
+                        Ok(tests) => TestReport {
+                            module: name.clone(),
+                            error: None,
+                            tests,
+                        },
+                        Err(e) => TestReport {
+                            module: name.clone(),
+                            error: Some(e.to_string()),
+                            tests: vec![],
+                        },
+                    }
+                })
+                .collect::<Vec<TestReport>>()
+        })
+        .await
+        .map_err(|e| warp::reject::custom(ProbeError::Eval(e.to_string())))?;
+        Ok(warp::reply::json(&reports))
+    }
+
+    async fn index_handler(&self) -> std::result::Result<impl Reply, Rejection> {
+        let mut body = String::from(
+            "<html>\n<head><title>jsonnet-exporter</title></head>\n<body>\n\
+             <h1>jsonnet-exporter</h1>\n\
+             <p><a href=\"/metrics\">Metrics</a></p>\n\
+             <h2>Modules</h2>\n<dl>\n",
+        );
+        for module in self.module_infos() {
+            body.push_str(&format!(
+                "<dt>{}</dt><dd>{}</dd>\n",
+                escape_html(&module.name),
+                escape_html(module.description.as_deref().unwrap_or("")),
+            ));
+        }
+        body.push_str("</dl>\n</body>\n</html>\n");
+        Ok(warp::reply::html(body))
+    }
+
+    async fn last_error_handler(
+        &self,
+        params: HashMap<String, String>,
+    ) -> std::result::Result<impl Reply, Rejection> {
+        if !self.opts.debug {
+            return Err(warp::reject::not_found());
+        }
+
+        let module_name = match params.get("module") {
+            Some(module_name) => module_name,
+            None => {
+                return Err(warp::reject::custom(ProbeError::MissingParameter(
+                    "module".into(),
+                )));
+            }
+        };
+
+        match self.last_errors.lock().unwrap().get(module_name) {
+            Some(e) => Ok(e.clone()),
+            None => Ok(format!("no error recorded for module '{}'", module_name)),
+        }
+    }
+
+    async fn background_scrape(&self, name: &str, module: &ConfigModule) {
+        let background = match &module.background {
+            Some(background) => background,
+            None => return,
+        };
+
+        let mut interval = tokio::time::interval(Duration::from_secs(background.interval_seconds));
+        loop {
+            interval.tick().await;
+
+            match self
+                .probe_targets(name, module, background.target.clone())
+                .await
+            {
+                Ok(families) => {
+                    self.background_families
+                        .lock()
+                        .unwrap()
+                        .insert(name.to_string(), families);
+                }
+                Err(e) => {
+                    error!("background scrape of module '{}' failed: {:?}", name, e);
+                    // the families of the last successful scrape are stale
+                    self.background_families
+                        .lock()
+                        .unwrap()
+                        .insert(name.to_string(), vec![probe_success_family(false)]);
+                }
+            };
+        }
+    }
+
+    /// Completes the creation times of all counter series. Series without a
+    /// creation time from the module keep the time they were first seen.
+    /// Series missing from the probe are forgotten, targets not probed for
+    /// `COUNTERS_CREATED_TTL` are evicted.
+    fn resolve_counters_created(
+        &self,
+        module_name: &str,
+        target: &str,
+        metric_families: &mut Vec<MetricFamily>,
+    ) {
+        let now = self.clock().now();
+        let mut counters_created = self.counters_created.lock().unwrap();
+        counters_created.retain(|_, c| now - c.probed_at < COUNTERS_CREATED_TTL);
+
+        let key = (module_name.to_string(), target.to_string());
+        let previous = counters_created
+            .remove(&key)
+            .map(|c| c.series)
+            .unwrap_or_default();
+        let mut series = HashMap::new();
+
+        let mut resolved = vec![];
+        for mf in metric_families
+            .iter()
+            .filter(|mf| mf.get_field_type() == prometheus::proto::MetricType::COUNTER)
+        {
+            let created_name = openmetrics::created_name(mf.get_name());
+            let known = metric_families
+                .iter()
+                .find(|created| created.get_name() == created_name);
+
+            let mut created = gauge_family(
+                &created_name,
+                &format!("Creation time of {}.", mf.get_name()),
+                &[],
+                0.0,
+            );
+            created.mut_metric().clear();
+
+            for m in mf.get_metric() {
+                let series_key = format!(
+                    "{}{:?}",
+                    mf.get_name(),
+                    m.get_label()
+                        .iter()
+                        .map(|l| (l.get_name(), l.get_value()))
+                        .collect::<Vec<_>>()
+                );
+                let known = known.and_then(|known| {
+                    known
+                        .get_metric()
+                        .iter()
+                        .find(|c| c.get_label() == m.get_label())
+                });
+                let created_at = match known {
+                    Some(known) => known.get_gauge().get_value(),
+                    None => previous.get(&series_key).copied().unwrap_or(now),
+                };
+                series.insert(series_key, created_at);
+
+                let mut c = prometheus::proto::Metric::default();
+                c.set_label(m.get_label().to_vec().into());
+                let mut gauge = prometheus::proto::Gauge::default();
+                gauge.set_value(created_at);
+                c.set_gauge(gauge);
+                created.mut_metric().push(c);
+            }
+            resolved.push(created);
+        }
+
+        counters_created.insert(
+            key,
+            CountersCreated {
+                probed_at: now,
+                series,
+            },
+        );
+
+        metric_families.retain(|mf| !resolved.iter().any(|r| r.get_name() == mf.get_name()));
+        metric_families.extend(resolved);
+    }
+
+    /// Probes a single target with the module, at most once per minimum
+    /// interval of the module. Probes within the interval get the result of
+    /// the last fetch, including its failure, probes during a fetch wait for
+    /// its result.
+    async fn probe(
+        &self,
+        module_name: &str,
+        module: &ConfigModule,
+        target: &str,
+    ) -> std::result::Result<Vec<MetricFamily>, ProbeError> {
+        let min_interval = match module.min_interval_seconds {
+            Some(min_interval) => Duration::from_secs(min_interval),
+            None => return self.probe_once(module_name, module, target).await,
+        };
+
+        let mut last_probes = self.last_probes.lock().unwrap();
+        // results past the minimum interval of their module are not reused,
+        // entries still referenced are being fetched or waited for
+        last_probes.retain(|(name, _), last| {
+            if Arc::strong_count(last) > 1 {
+                return true;
+            }
+            let min_interval = self
+                .config
+                .modules
+                .get(name)
+                .and_then(|m| m.min_interval_seconds);
+            match (last.try_lock().as_deref(), min_interval) {
+                (Ok(Some(last)), Some(min_interval)) => {
+                    last.fetched_at.elapsed() < Duration::from_secs(min_interval)
+                }
+                _ => false,
+            }
+        });
+        let last_probe = last_probes
+            .entry((module_name.to_string(), target.to_string()))
+            .or_insert_with(|| Arc::new(tokio::sync::Mutex::new(None)))
+            .clone();
+        drop(last_probes);
+        // marks the target as being fetched until the result is recorded
+        let mut last_probe = last_probe.lock().await;
+        if let Some(last) = &*last_probe {
+            if last.fetched_at.elapsed() < min_interval {
+                debug!("module '{}' rate limited, reusing last result", module_name);
+                return last.result.clone().map_err(ProbeError::RateLimited);
+            }
+        }
+
+        let fetched_at = Instant::now();
+        let result = self.probe_once(module_name, module, target).await;
+        *last_probe = Some(LastProbe {
+            fetched_at,
+            result: match &result {
+                Ok(metric_families) => Ok(metric_families.clone()),
+                Err(e) => Err(format!("{:?}", e)),
+            },
+        });
+        result
+    }
+
+    /// Fetches a single target and evaluates the module.
+    async fn probe_once(
+        &self,
+        module_name: &str,
+        module: &ConfigModule,
+        target: &str,
+    ) -> std::result::Result<Vec<MetricFamily>, ProbeError> {
+        let key = (module_name.to_string(), target.to_string());
+        let mut fetched = match module.fetch(target, self.credential(module)?).await {
+            Ok(fetched) => fetched,
+            Err(ProbeError::TargetTLSVerification(reason, cert_expiry)) => {
+                let mut metric_families = vec![
+                    probe_success_family(false),
+                    gauge_family(
+                        "probe_tls_verification_failed",
+                        "Reason the TLS verification of the target failed",
+                        &[("reason", &reason)],
+                        1.0,
+                    ),
+                ];
+                metric_families.extend(cert_expiry.map(cert_expiry_family));
+                return Ok(metric_families);
+            }
+            Err(e) => return Err(e),
+        };
+
+        info!("{:?}", fetched.data);
+
+        let mut metric_families = match self.eval_module(module_name, module, &mut fetched) {
+            Err(ProbeError::Eval(e)) if module.fallback.is_some() => {
+                self.eval_fallback(module_name, module, &fetched, e)?
+            }
+            result => result?,
+        };
+        self.resolve_counters_created(module_name, target, &mut metric_families);
+
+        metric_families.extend(fetched.cert_expiry.map(cert_expiry_family));
+
+        metric_families.push(probe_success_family(true));
+
+        if module.max_body_bytes.is_some() {
+            metric_families.push(gauge_family(
+                "probe_body_size_bytes",
+                "Size of the response body before truncation.",
+                &[],
+                fetched.body_size as f64,
+            ));
+        }
+
+        if module.instance_label {
+            if let Some(instance) = target_instance(target) {
+                add_labels(&mut metric_families, &[("instance", &instance)]);
+            }
+        }
+
+        if self.opts.metrics_include_probes > 0 && module.background.is_none() {
+            let mut labeled = metric_families.clone();
+            add_labels(&mut labeled, &[("module", module_name), ("target", target)]);
+
+            let mut probe_families = self.probe_families.lock().unwrap();
+            probe_families.retain(|(k, _)| k != &key);
+            probe_families.push((key, labeled));
+            if probe_families.len() > self.opts.metrics_include_probes {
+                probe_families.remove(0);
+            }
+        }
+
+        Ok(metric_families)
+    }
+
+    /// Evaluates the fallback module of a module, which evaluation failed.
+    /// The fallback gets the error of the module passed as `input.error`.
+    fn eval_fallback(
+        &self,
+        module_name: &str,
+        module: &ConfigModule,
+        fetched: &Fetched,
+        error: String,
+    ) -> std::result::Result<Vec<MetricFamily>, ProbeError> {
+        let fallback_name = module.fallback.as_deref().unwrap_or_default();
+        let fallback = match self.config.modules.get(fallback_name) {
+            Some(fallback) => fallback,
+            None => return Err(ProbeError::ModuleNotFound(fallback_name.to_string())),
+        };
+        info!(
+            "module '{}' failed, evaluating fallback '{}': {}",
+            module_name, fallback_name, error
+        );
+
+        let mut data = match &fetched.data {
+            serde_json::Value::Object(data) => data.clone(),
+            _ => serde_json::Map::new(),
+        };
+        data.insert("error".to_string(), serde_json::Value::String(error));
+        let mut fetched = Fetched {
+            data: serde_json::Value::Object(data),
+            stream: None,
+            body_size: fetched.body_size,
+            cert_expiry: None,
+        };
+        self.eval_module(fallback_name, fallback, &mut fetched)
+    }
+
+    /// Returns the header and value of the credential of the module, the
+    /// credential file is re-read once its refresh interval passed.
+    fn credential(
+        &self,
+        module: &ConfigModule,
+    ) -> std::result::Result<Option<(String, String)>, ProbeError> {
+        let credentials = match &module.credentials {
+            Some(credentials) => credentials,
+            None => return Ok(None),
+        };
+        let path = self.config.base_dir.join(&credentials.file);
+
+        let mut cache = self.credentials.lock().unwrap();
+        let value = match cache.get(&path) {
+            Some((read_at, value))
+                if read_at.elapsed() < Duration::from_secs(credentials.refresh_seconds) =>
+            {
+                value.clone()
+            }
+            _ => {
+                debug!("reading credential file {:?}", path);
+                let value = std::fs::read_to_string(&path)
+                    .map_err(|e| ProbeError::Credentials(format!("cannot read {:?}: {}", path, e)))?
+                    .trim()
+                    .to_string();
+                cache.insert(path, (Instant::now(), value.clone()));
+                value
+            }
+        };
+
+        Ok(Some((
+            credentials.header.clone(),
+            format!("{}{}", credentials.prefix, value),
+        )))
+    }
+
+    /// Probes the given target, or all targets configured for the module with
+    /// their labels attached. A failing configured target is logged and
+    /// reported as `probe_success 0`, the other targets are still probed.
+    async fn probe_targets(
+        &self,
+        module_name: &str,
+        module: &ConfigModule,
+        target: Option<String>,
+    ) -> std::result::Result<Vec<MetricFamily>, ProbeError> {
+        if let Some(target) = target {
+            return self.probe(module_name, module, &target).await;
+        }
+
+        let targets = match &module.targets {
+            Some(targets) => targets,
+            None => return Err(ProbeError::MissingParameter("target".into())),
+        };
+
+        let mut metric_families = vec![];
+        for target in targets {
+            let mut target_families = match self.probe(module_name, module, &target.url).await {
+                Ok(target_families) => target_families,
+                Err(e) => {
+                    error!(
+                        "probe of target '{}' of module '{}' failed: {:?}",
+                        target.url, module_name, e
+                    );
+                    vec![probe_success_family(false)]
+                }
+            };
+            let labels: Vec<(&str, &str)> = target
+                .labels
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect();
+            add_labels(&mut target_families, &labels);
+            metric_families.extend(target_families);
+        }
+        Ok(metric_families)
+    }
+
+    async fn probe_handler(
+        &self,
+        params: HashMap<String, String>,
+        headers: HeaderMap,
+    ) -> std::result::Result<impl Reply, Rejection> {
+        let module_name = probe_param(&params, &headers, "module", "x-jsonnet-module")?;
+
+        let module = match self.config.modules.get(&module_name) {
+            Some(m) => m,
+            None => {
+                return Err(warp::reject::custom(ProbeError::ModuleNotFound(
+                    module_name.clone(),
+                )))
+            }
+        };
+
+        let target = probe_param(&params, &headers, "target", "x-jsonnet-target").ok();
+
+        let mut metric_families = self
+            .probe_targets(&module_name, module, target)
+            .await
+            .map_err(warp::reject::custom)?;
+
+        if module.module_info {
+            metric_families.push(gauge_family(
+                "probe_module_info",
+                "Information about the module used for the probe",
+                &[("module", &module_name), ("version", &module.version())],
+                1.0,
+            ));
+        }
+
+        // the module may define metrics the exporter adds as well
+        let mut metric_families = merge_families(metric_families)
+            .map_err(|e| warp::reject::custom(ProbeError::Eval(e.to_string())))?;
+        sort_labels(&mut metric_families, &self.config.label_order);
+
+        let openmetrics = match headers.get(ACCEPT).map(|v| v.to_str()) {
+            Some(Ok(accept)) => accept.contains("application/openmetrics-text"),
+            _ => false,
+        };
+        let (content_type, body) = if params.get("format").map(String::as_str) == Some("graphite") {
+            (
+                graphite::FORMAT_TYPE.to_string(),
+                graphite::encode(&metric_families, self.clock().now() as i64),
+            )
+        } else if openmetrics {
+            (
+                openmetrics::FORMAT_TYPE.to_string(),
+                openmetrics::encode(&metric_families),
+            )
+        } else {
+            (
+                TextEncoder::new().format_type().to_string(),
+                encode_families(&metric_families).unwrap(),
+            )
+        };
+
+        let accept_encoding = headers.get(ACCEPT_ENCODING).and_then(|v| v.to_str().ok());
+        let (response, body) = compress(Response::builder(), accept_encoding, body.into_bytes());
+
+        Ok(response
+            .header(CONTENT_TYPE, content_type)
+            .body(body)
+            .unwrap())
+    }
+}
+
+lazy_static! {
+    static ref APP: App = App::new();
+}
+
+// streamed bodies are read within `block_in_place`, `main` must keep the
+// default multi threaded runtime
+#[tokio::main]
+async fn main() {
+    match &APP.opts.subcmd {
+        Some(SubCommand::Bench(bench)) => {
+            print!("{}", APP.bench(bench).expect("cannot benchmark module"));
+            return;
+        }
+        Some(SubCommand::Eval(eval)) => {
+            match APP.eval(eval) {
+                Ok(output) => print!("{}", output),
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(SubCommand::Test(test)) => {
+            if !APP.test(test) {
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(SubCommand::RunOnce(run_once)) => {
+            match APP.run_once(run_once).await {
+                Ok(output) => print!("{}", output),
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        None => {}
+    }
+
+    APP.config
+        .validate(&APP.parsed)
+        .expect("cannot validate config file");
+
+    for (name, module) in &APP.config.modules {
+        if module.background.is_some() {
+            tokio::spawn(APP.background_scrape(name, module));
+        }
+    }
+
+    // GET /hello/warp => 200 OK with body "Hello, warp!"
+    let hello = warp::path!("hello" / String).map(|name| format!("Hello, {}!", name));
+
+    let metrics = warp::path!("metrics")
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and_then(|inm, ae| APP.metrics_handler(None, inm, ae));
+
+    let metrics_group = warp::path!("metrics" / String)
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and_then(|group, inm, ae| APP.metrics_handler(Some(group), inm, ae));
+
+    let probe = warp::path!("probe")
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::header::headers_cloned())
+        .and_then(|p, h| APP.probe_handler(p, h));
+
+    let last_error = warp::path!("debug" / "last-error")
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(|p| APP.last_error_handler(p));
+
+    let index = warp::path::end().and_then(|| APP.index_handler());
+
+    let modules = warp::path!("modules").and_then(|| APP.modules_handler());
+
+    let tests = warp::path!("tests").and_then(|| APP.tests_handler());
+
+    let routes = warp::get().and(
+        index
+            .or(hello)
+            .or(metrics)
+            .or(metrics_group)
+            .or(modules)
+            .or(tests)
+            .or(probe)
+            .or(last_error),
+    );
+    // Parse address used to bind exporter to.
+    let addr: SocketAddr = APP
+        .opts
+        .bind_addr
+        .parse()
+        .expect("can not parse listen addr");
+
+    warp::serve(routes).run(addr).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Builds an app from command line arguments and a config, paths of the
+    /// config are relative to the repository.
+    fn app(args: &[&str], config: &str) -> App {
+        let opts =
+            Opts::parse_from(std::iter::once("jsonnet-exporter").chain(args.iter().copied()));
+        let mut config: Config = serde_yaml::from_str(config).unwrap();
+        config.base_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        App::with_config(opts, config, String::new())
+    }
+
+    /// Builds a module from its config, paths of the config are relative to
+    /// the repository.
+    fn module(config: &str) -> Module {
+        serde_yaml::from_str::<ConfigModule>(config)
+            .unwrap()
+            .state(
+                Path::new(env!("CARGO_MANIFEST_DIR")),
+                &[],
+                &ParseCache::default(),
+                Clock::Fixed(1600000000.0),
+            )
+            .unwrap()
+    }
+
+    async fn body(reply: impl Reply) -> String {
+        let body = hyper::body::to_bytes(reply.into_response().into_body())
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn self_metrics_namespace() {
+        let app = app(&["--metrics-namespace", "custom"], "modules: {}");
+        let body = body(app.metrics_handler(None, None, None).await.unwrap()).await;
+        assert!(body.contains("\ncustom_http_requests_total{handler=\"all\"}"));
+        assert!(!body.contains("jsonnet_exporter_"));
+    }
+
+    #[test]
+    fn meta_metrics_count_filtered_module_samples() {
+        let module = module(
+            r#"
+meta_metrics: true
+label_cardinality: true
+allowed_metrics: [requests_total, temperature]
+jsonnet: |
+  { process(input):: {
+    requests_total: {
+      type: 'counter',
+      label_names: ['code'],
+      series: [
+        { label_values: ['200'], value: 3, created_ms: 1000 },
+        { label_values: ['500'], value: 1, created_ms: 1000 },
+      ],
+    },
+    temperature: {
+      type: 'gauge',
+      label_names: ['room'],
+      series: [
+        { label_values: ['kitchen'], value: 20 },
+        { label_values: ['kitchen'], value: 21 },
+      ],
+    },
+  } }
+"#,
+        );
+        let output =
+            encode_families(&module.eval_families(&serde_json::json!({})).unwrap()).unwrap();
+        assert!(output.contains("\nprobe_samples_scraped 4\n"), "{}", output);
+        assert!(
+            output.contains("\nprobe_samples_post_filtering 3\n"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn deep_merge_replaces_arrays_by_default() {
+        let module = module(
+            r#"
+jsonnet: |
+  local deepMerge = std.native('deepMerge');
+  { process(input):: {
+    merged: {
+      type: 'gauge',
+      series: [{ value: std.length(deepMerge({ a: [1] }, { a: [2] }).a) }],
+    },
+    concatenated: {
+      type: 'gauge',
+      series: [{ value: std.length(deepMerge({ a: [1] }, { a: [2] }, true).a) }],
+    },
+  } }
+"#,
+        );
+        let output =
+            encode_families(&module.eval_families(&serde_json::json!({})).unwrap()).unwrap();
+        assert!(output.contains("\nmerged 1\n"), "{}", output);
+        assert!(output.contains("\nconcatenated 2\n"), "{}", output);
+    }
+
+    #[test]
+    fn series_value_required_unless_histogram() {
+        let module = module(
+            r#"
+jsonnet: |
+  { process(input):: {
+    [input.type]: {
+      type: input.type,
+      buckets: [1],
+      series: [{ bucket_counts: [1], sum: 0.5 }],
+    },
+  } }
+"#,
+        );
+        let eval = |r#type| module.eval_families(&serde_json::json!({ "type": r#type }));
+
+        assert!(eval("histogram").is_ok());
+        for r#type in &["gauge", "counter", "untyped"] {
+            let e = eval(r#type).err().unwrap().to_string();
+            assert_eq!(e, format!("series of metric '{}' has no value", r#type));
+        }
+    }
+
+    #[test]
+    fn jsonnet_path_relative_to_base_dir() {
+        let base_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("lib");
+        let module: ConfigModule = serde_yaml::from_str("jsonnet_path: memory.jsonnet").unwrap();
+        let module = module
+            .state(&base_dir, &[], &ParseCache::default(), Clock::System)
+            .unwrap();
+        assert_eq!(*module.path, base_dir.join("memory.jsonnet"));
+    }
+
+    #[test]
+    fn jpath_searched_after_library_paths() {
+        let base_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("lib");
+        let module: ConfigModule = serde_yaml::from_str(
+            "{ jsonnet_path: memory.jsonnet, import_extensions: [.libsonnet] }",
+        )
+        .unwrap();
+        let input = serde_json::json!({ "body": { "used": 2048, "total": 4096 } });
+        let eval = |jpath: &[PathBuf]| {
+            module
+                .state(&base_dir, jpath, &ParseCache::default(), Clock::System)?
+                .eval_families(&input)
+        };
+
+        assert!(eval(&[]).is_err());
+        let output = encode_families(&eval(&[base_dir.join("vendor")]).unwrap()).unwrap();
+        assert!(output.contains("\nmemory_used_percent 50\n"), "{}", output);
+    }
+
+    #[test]
+    fn inline_jsonnet_rejects_library_paths() {
+        let module: ConfigModule =
+            serde_yaml::from_str("{ jsonnet: '{}', library_paths: [lib] }").unwrap();
+        let error = module
+            .validate(Path::new("."), &[], &ParseCache::default())
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("library_paths"), "{}", error);
+    }
+
+    #[test]
+    fn probe_param_from_query_or_header() {
+        let mut params = HashMap::new();
+        params.insert("module".to_string(), "query".to_string());
+        let mut headers = HeaderMap::new();
+        headers.insert("x-jsonnet-module", HeaderValue::from_static("header"));
+        headers.insert(
+            "x-jsonnet-target",
+            HeaderValue::from_static("http://target"),
+        );
+
+        let param = |name, header| probe_param(&params, &headers, name, header).ok();
+        assert_eq!(param("module", "x-jsonnet-module"), Some("query".into()));
+        assert_eq!(
+            param("target", "x-jsonnet-target"),
+            Some("http://target".into())
+        );
+        assert!(matches!(
+            probe_param(&HashMap::new(), &HeaderMap::new(), "target", "x-jsonnet-target"),
+            Err(ProbeError::MissingParameter(name)) if name == "target"
+        ));
+    }
+
+    const BACKGROUND_CONFIG: &str = r#"
+modules:
+  fast:
+    jsonnet: "{}"
+    background: { target: "http://localhost" }
+  slow:
+    jsonnet: "{}"
+    background: { target: "http://localhost", group: slow }
+"#;
+
+    fn set_background_families(app: &App, module: &str, families: Vec<MetricFamily>) {
+        app.background_families
+            .lock()
+            .unwrap()
+            .insert(module.to_string(), families);
+    }
+
+    #[tokio::test]
+    async fn metrics_groups() {
+        let app = app(&[], BACKGROUND_CONFIG);
+        set_background_families(
+            &app,
+            "fast",
+            vec![gauge_family("fast_up", "fast", &[], 1.0)],
+        );
+        set_background_families(
+            &app,
+            "slow",
+            vec![gauge_family("slow_up", "slow", &[], 1.0)],
+        );
+
+        let body_default = body(app.metrics_handler(None, None, None).await.unwrap()).await;
+        assert!(body_default.contains("\nfast_up 1\n"));
+        assert!(!body_default.contains("slow_up"));
+
+        let body_slow = body(
+            app.metrics_handler(Some("slow".into()), None, None)
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert!(body_slow.contains("\nslow_up 1\n"));
+        assert!(!body_slow.contains("fast_up"));
+        assert!(!body_slow.contains("jsonnet_exporter_"));
+
+        let missing = app
+            .metrics_handler(Some("missing".into()), None, None)
+            .await
+            .err()
+            .unwrap();
+        assert!(missing.is_not_found());
+    }
+
+    #[tokio::test]
+    async fn duplicate_metrics() {
+        let config = |duplicate_metrics| {
+            format!(
+                r#"
+duplicate_metrics: {}
+modules:
+  a:
+    jsonnet: "{{}}"
+    background: {{ target: "http://localhost" }}
+  b:
+    jsonnet: "{{}}"
+    background: {{ target: "http://localhost" }}
+"#,
+                duplicate_metrics
+            )
+        };
+        let set_families = |app: &App| {
+            set_background_families(app, "a", vec![gauge_family("up", "a is up", &[], 1.0)]);
+            set_background_families(app, "b", vec![gauge_family("up", "b is up", &[], 0.0)]);
+        };
+
+        let namespaced = app(&[], &config("namespace"));
+        set_families(&namespaced);
+        let output = body(namespaced.metrics_handler(None, None, None).await.unwrap()).await;
+        assert!(output.contains("\nup 1\n"), "{}", output);
+        assert!(output.contains("\nb_up 0\n"), "{}", output);
+
+        let rejected = app(&[], &config("error"));
+        set_families(&rejected);
+        let response = rejected
+            .metrics_handler(None, None, None)
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), 500);
+
+        // the same series of both modules is a duplicate as well
+        let set_families = |app: &App| {
+            set_background_families(app, "a", vec![gauge_family("up", "is up", &[], 1.0)]);
+            set_background_families(app, "b", vec![gauge_family("up", "is up", &[], 0.0)]);
+        };
+
+        let namespaced = app(&[], &config("namespace"));
+        set_families(&namespaced);
+        let output = body(namespaced.metrics_handler(None, None, None).await.unwrap()).await;
+        assert!(output.contains("\nup 1\n"), "{}", output);
+        assert!(output.contains("\nb_up 0\n"), "{}", output);
+
+        let rejected = app(&[], &config("error"));
+        set_families(&rejected);
+        let output = body(rejected.metrics_handler(None, None, None).await.unwrap()).await;
+        assert_eq!(
+            output,
+            "cannot merge metrics: duplicate series of metric 'up' with labels []"
+        );
+    }
+
+    /// Makes the CA of the TLS test certificates the only trusted one.
+    fn trust_test_ca() {
+        static TRUST: std::sync::Once = std::sync::Once::new();
+        TRUST.call_once(|| {
+            std::env::set_var(
+                "SSL_CERT_FILE",
+                Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/tls/ca.pem"),
+            )
+        });
+    }
+
+    /// Serves `{}` over HTTPS on an ephemeral local port, with the identity
+    /// of a PKCS #12 file in `testdata/tls`. `/http/<authority><path>`
+    /// redirects to the same path over plain HTTP.
+    async fn serve_tls(identity: &str) -> SocketAddr {
+        trust_test_ca();
+        let identity = std::fs::read(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("testdata/tls")
+                .join(identity),
+        )
+        .unwrap();
+        let identity = native_tls::Identity::from_pkcs12(&identity, "test").unwrap();
+        let acceptor =
+            tokio_native_tls::TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    // handshakes with rejected certificates fail
+                    if let Ok(stream) = acceptor.accept(stream).await {
+                        let service = hyper::service::service_fn(|req| async move {
+                            match req.uri().path().strip_prefix("/http/") {
+                                Some(location) => Response::builder()
+                                    .status(302)
+                                    .header(LOCATION, format!("http://{}", location))
+                                    .body(hyper::Body::empty()),
+                                None => Response::builder()
+                                    .header(CONTENT_TYPE, "application/json")
+                                    .body(hyper::Body::from("{}")),
+                            }
+                        });
+                        let _ = hyper::server::conn::Http::new()
+                            .serve_connection(stream, service)
+                            .await;
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn tls_cert_expiry() {
+        let valid = serve_tls("localhost.p12").await;
+        let expired = serve_tls("expired.p12").await;
+        let app = app(
+            &[],
+            r#"
+modules:
+  expiry:
+    tls_cert_expiry: true
+    strict_tls: true
+    jsonnet: "{ process(input):: {} }"
+"#,
+        );
+        let (app, module) = (&app, &app.config.modules["expiry"]);
+        let probe = |addr: SocketAddr| {
+            let target = format!("https://{}/", addr);
+            async move { app.probe("expiry", module, &target).await.unwrap() }
+        };
+
+        let output = encode_families(&probe(valid).await).unwrap();
+        // 2120-01-01T00:00:00Z
+        assert!(
+            output.contains("\nprobe_ssl_earliest_cert_expiry 4733510400\n"),
+            "{}",
+            output
+        );
+
+        // the certificate is read, even though it fails verification
+        let output = encode_families(&probe(expired).await).unwrap();
+        // 2001-01-01T00:00:00Z
+        assert!(
+            output.contains("\nprobe_ssl_earliest_cert_expiry 978307200\n"),
+            "{}",
+            output
+        );
+        assert!(output.contains("\nprobe_success 0\n"), "{}", output);
+    }
+
+    #[tokio::test]
+    async fn strict_tls() {
+        let valid = serve_tls("localhost.p12").await;
+        let expired = serve_tls("expired.p12").await;
+        let app = app(
+            &[],
+            r#"
+modules:
+  strict:
+    strict_tls: true
+    jsonnet: "{ process(input):: {} }"
+"#,
+        );
+        let module = &app.config.modules["strict"];
+
+        let metric_families = app
+            .probe("strict", module, &format!("https://{}/", valid))
+            .await
+            .unwrap();
+        let output = encode_families(&metric_families).unwrap();
+        assert!(output.contains("\nprobe_success 1\n"), "{}", output);
+
+        let metric_families = app
+            .probe("strict", module, &format!("https://{}/", expired))
+            .await
+            .unwrap();
+        let output = encode_families(&metric_families).unwrap();
+        assert!(output.contains("\nprobe_success 0\n"), "{}", output);
+        assert!(
+            output.contains("\nprobe_tls_verification_failed{reason=\"expired\"} 1\n"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn dns_name_matches_wildcards() {
+        assert!(dns_name_matches("example.com", "EXAMPLE.com."));
+        assert!(dns_name_matches("*.example.com", "www.example.com"));
+        assert!(!dns_name_matches("*.example.com", "example.com"));
+        assert!(!dns_name_matches("*.example.com", "a.www.example.com"));
+        assert!(!dns_name_matches("www.example.com", "example.com"));
+    }
+
+    #[tokio::test]
+    async fn probe_conflicting_module_metric() {
+        let (addr, server) = warp::serve(warp::any().map(|| warp::reply::json(&"ok")))
+            .bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let app = app(
+            &[],
+            r#"
+modules:
+  info:
+    module_info: true
+    jsonnet: |
+      { process(input):: {
+        probe_module_info: { type: 'counter', series: [{ value: 1 }] },
+      } }
+"#,
+        );
+        let mut params = HashMap::new();
+        params.insert("module".to_string(), "info".to_string());
+        params.insert("target".to_string(), format!("http://{}/", addr));
+
+        let rejection = app
+            .probe_handler(params, HeaderMap::new())
+            .await
+            .err()
+            .unwrap();
+        match rejection.find::<ProbeError>() {
+            Some(ProbeError::Eval(e)) => assert!(e.contains("probe_module_info"), "{}", e),
+            e => panic!("unexpected rejection {:?}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn min_interval_rate_limits_fetches() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let routes = warp::path!(String).map(move |path: String| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let body = if path == "valid" { "{}" } else { "{" };
+            warp::reply::with_header(body, "content-type", "application/json")
+        });
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let app = app(
+            &[],
+            r#"
+modules:
+  limited:
+    min_interval_seconds: 60
+    jsonnet: "{ process(input):: {} }"
+"#,
+        );
+        let module = &app.config.modules["limited"];
+
+        let target = format!("http://{}/valid", addr);
+        let probes = (0..10).map(|_| app.probe("limited", module, &target));
+        for result in futures::future::join_all(probes).await {
+            assert!(result.is_ok());
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        // failures are reused as well
+        let target = format!("http://{}/invalid", addr);
+        assert!(matches!(
+            app.probe("limited", module, &target).await,
+            Err(ProbeError::TargetJSONParse(_))
+        ));
+        assert!(matches!(
+            app.probe("limited", module, &target).await,
+            Err(ProbeError::RateLimited(_))
+        ));
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn min_interval_evicts_expired_probes() {
+        let addr = serve_values();
+        let app = app(
+            &[],
+            r#"
+modules:
+  limited:
+    min_interval_seconds: 60
+    jsonnet: "{ process(input):: {} }"
+"#,
+        );
+        let module = &app.config.modules["limited"];
+
+        let expired = ("limited".to_string(), "http://expired".to_string());
+        app.last_probes.lock().unwrap().insert(
+            expired.clone(),
+            Arc::new(tokio::sync::Mutex::new(Some(LastProbe {
+                fetched_at: Instant::now() - Duration::from_secs(61),
+                result: Ok(vec![]),
+            }))),
+        );
+
+        let target = format!("http://{}/1", addr);
+        assert!(app.probe("limited", module, &target).await.is_ok());
+
+        let last_probes = app.last_probes.lock().unwrap();
+        assert!(!last_probes.contains_key(&expired));
+        assert!(last_probes.contains_key(&("limited".to_string(), target)));
+    }
+
+    fn fetched(data: serde_json::Value) -> Fetched {
+        Fetched {
+            data,
+            stream: None,
+            body_size: 0,
+            cert_expiry: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn last_error() {
+        let config = r#"
+modules:
+  failing:
+    jsonnet: "{ process(input):: error 'boom' }"
+"#;
+        let mut params = HashMap::new();
+        params.insert("module".to_string(), "failing".to_string());
+
+        let debug = app(&["--debug"], config);
+        let module = &debug.config.modules["failing"];
+        assert!(debug
+            .eval_module("failing", module, &mut fetched(serde_json::json!({})))
+            .is_err());
+        let error = body(debug.last_error_handler(params.clone()).await.unwrap()).await;
+        assert!(error.contains("boom"), "{}", error);
+
+        let disabled = app(&[], config);
+        assert!(disabled
+            .last_error_handler(params)
+            .await
+            .err()
+            .unwrap()
+            .is_not_found());
+    }
+
+    #[tokio::test]
+    async fn metrics_include_conflicting_probes() {
+        let app = app(&["--metrics-include-probes", "2"], "modules: {}");
+        app.probe_families.lock().unwrap().push((
+            ("web".to_string(), "http://target".to_string()),
+            vec![gauge_family(
+                "jsonnet_exporter_http_requests_total",
+                "Requests of the target.",
+                &[("module", "web"), ("target", "http://target")],
+                5.0,
+            )],
+        ));
+
+        let response = app
+            .metrics_handler(None, None, None)
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), 200);
+        let body = body(response).await;
+        assert!(
+            body.contains(
+                "\nweb_jsonnet_exporter_http_requests_total{module=\"web\",target=\"http://target\"} 5\n"
+            ),
+            "{}",
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_background_scrape_clears_families() {
+        // nothing listens on the port of a dropped listener
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let app = app(
+            &[],
+            &format!(
+                "modules: {{ background: {{ background: {{ target: "http://{}/" }}, jsonnet: '{{}}' }} }}",
+                addr
+            ),
+        );
+        set_background_families(
+            &app,
+            "background",
+            vec![gauge_family("stale", "stale", &[], 1.0)],
+        );
+
+        let module = &app.config.modules["background"];
+        tokio::select! {
+            _ = app.background_scrape("background", module) => unreachable!(),
+            _ = async {
+                while app.background_families.lock().unwrap()["background"][0].get_name()
+                    == "stale"
+                {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            } => {}
+        }
+
+        let output = body(app.metrics_handler(None, None, None).await.unwrap()).await;
+        assert!(!output.contains("\nstale 1\n"), "{}", output);
+        assert!(output.contains("\nprobe_success 0\n"), "{}", output);
+    }
+
+    #[tokio::test]
+    async fn metrics_include_probes() {
+        let addr = serve_values();
+        let app = app(
+            &["--metrics-include-probes", "2"],
+            &r#"
+modules:
+  values:
+    jsonnet: "JSONNET"
+  background:
+    background: { target: "http://ADDR/2" }
+    jsonnet: "JSONNET"
+"#
+            .replace("ADDR", &addr.to_string())
+            .replace("JSONNET", VALUE_MODULE),
+        );
+
+        let mut params = HashMap::new();
+        params.insert("module".to_string(), "values".to_string());
+        params.insert("target".to_string(), format!("http://{}/1", addr));
+        app.probe_handler(params, HeaderMap::new()).await.unwrap();
+
+        let module = &app.config.modules["background"];
+        tokio::select! {
+            _ = app.background_scrape("background", module) => unreachable!(),
+            _ = async {
+                while app.background_families.lock().unwrap().is_empty() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            } => {}
+        }
+
+        let output = body(app.metrics_handler(None, None, None).await.unwrap()).await;
+        assert!(
+            output.contains(&format!(
+                "\nvalue{{module=\"values\",target=\"http://{}/1\"}} 1\n",
+                addr
+            )),
+            "{}",
+            output
+        );
+        // the background module is exported once, without probe labels
+        assert!(output.contains("\nvalue 2\n"), "{}", output);
+        assert!(!output.contains("module=\"background\""), "{}", output);
+    }
+
+    /// Serves `{"value": N}` on `/N`, on an ephemeral local port.
+    fn serve_values() -> SocketAddr {
+        let routes = warp::path!(f64)
+            .map(|value: f64| warp::reply::json(&serde_json::json!({ "value": value })));
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        addr
+    }
+
+    const VALUE_MODULE: &str =
+        "{ process(input):: { value: { type: 'gauge', series: [{ value: input.body.value }] } } }";
+
+    #[tokio::test]
+    async fn probe_static_targets_with_labels() {
+        let addr = serve_values();
+        let app = app(
+            &[],
+            &r#"
+modules:
+  static:
+    targets:
+      - url: http://ADDR/1
+        labels: { site: a }
+      - url: http://ADDR/2
+        labels: { site: b }
+    jsonnet: "JSONNET"
+"#
+            .replace("ADDR", &addr.to_string())
+            .replace("JSONNET", VALUE_MODULE),
+        );
+        let module = &app.config.modules["static"];
+
+        let metric_families = app.probe_targets("static", module, None).await.unwrap();
+        let output = encode_families(&merge_families(metric_families).unwrap()).unwrap();
+        assert!(
+            output.contains("\nvalue{site=\"a\"} 1\nvalue{site=\"b\"} 2\n"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn validate_target_labels_unique() {
+        let config = |labels: &str| {
+            let mut config: Config = serde_yaml::from_str(&format!(
+                r#"
+modules:
+  static:
+    targets:
+      - url: http://a
+        labels: {{ site: a }}
+      - url: http://b
+        labels: {}
+    jsonnet: "{{ process(input):: {{}} }}"
+"#,
+                labels
+            ))
+            .unwrap();
+            config.base_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+            config
+        };
+
+        assert!(config("{ site: b }")
+            .validate(&ParseCache::default())
+            .is_ok());
+        assert_eq!(
+            config("{ site: a }")
+                .validate(&ParseCache::default())
+                .err()
+                .unwrap()
+                .to_string(),
+            "module 'static' targets 'http://a' and 'http://b' have the same labels"
+        );
+    }
+
+    #[tokio::test]
+    async fn probe_static_targets_isolate_failures() {
+        let addr = serve_values();
+        // nothing listens on the port of a dropped listener
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let app = app(
+            &[],
+            &r#"
+modules:
+  static:
+    targets:
+      - url: http://ADDR/1
+        labels: { site: a }
+      - url: http://CLOSED/2
+        labels: { site: b }
+    jsonnet: "JSONNET"
+"#
+            .replace("ADDR", &addr.to_string())
+            .replace("CLOSED", &closed.to_string())
+            .replace("JSONNET", VALUE_MODULE),
+        );
+        let module = &app.config.modules["static"];
+
+        let metric_families = app.probe_targets("static", module, None).await.unwrap();
+        let output = encode_families(&merge_families(metric_families).unwrap()).unwrap();
+        assert!(output.contains("\nvalue{site=\"a\"} 1\n"), "{}", output);
+        assert!(
+            output.contains("\nprobe_success{site=\"b\"} 0\n"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn validate_test_input() {
+        let module: ConfigModule = serde_yaml::from_str(
+            r#"
+validate_test_input: true
+jsonnet: "{ process(input):: {} }"
+tests:
+  - input: '{"body": {}}'
+    output: ""
+  - input: '{"name": "missing body"}'
+    output: ""
+"#,
+        )
+        .unwrap();
+        let error = module
+            .run_tests(Path::new("."), &[], &ParseCache::default())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            error.starts_with("test #1 input is not valid JSON"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn instance_label() {
+        assert_eq!(
+            target_instance("https://example.com/metrics"),
+            Some("example.com:443".into())
+        );
+        assert_eq!(
+            target_instance("http://example.com:8080/"),
+            Some("example.com:8080".into())
+        );
+        assert_eq!(target_instance("http://[::1]/"), Some("[::1]:80".into()));
+
+        // labels set by the module take precedence
+        let mut metric_families = vec![
+            gauge_family("a", "", &[], 1.0),
+            gauge_family("b", "", &[("instance", "module")], 1.0),
+        ];
+        add_labels(&mut metric_families, &[("instance", "example.com:443")]);
+        let output = encode_families(&metric_families).unwrap();
+        assert!(output.contains("\na{instance=\"example.com:443\"} 1\n"));
+        assert!(output.contains("\nb{instance=\"module\"} 1\n"));
+    }
+
+    #[test]
+    fn parse_cache() {
+        let parsed = Arc::new(ParseCache::default());
+        let cached = |parsed: &ParseCache| parsed.0.lock().unwrap().len();
+        let parses = JSONNET_PARSES.get();
+
+        let path = Rc::new(PathBuf::from("parse_cache.jsonnet"));
+        parsed.parse(&path, "{ a: 1 }").unwrap();
+        assert_eq!(cached(&parsed), 1);
+        assert!(JSONNET_PARSES.get() > parses);
+
+        // the cache is shared by all threads
+        let shared = parsed.clone();
+        std::thread::spawn(move || {
+            let path = Rc::new(PathBuf::from("parse_cache.jsonnet"));
+            shared.parse(&path, "{ a: 1 }").unwrap();
+            assert_eq!(cached(&shared), 1);
+
+            // changed code is parsed again
+            shared.parse(&path, "{ a: 2 }").unwrap();
+        })
+        .join()
+        .unwrap();
+        assert_eq!(cached(&parsed), 2);
+    }
+
+    #[tokio::test]
+    async fn max_body_bytes_truncates() {
+        let routes = warp::any().map(|| "0123456789".repeat(100));
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let module: ConfigModule = serde_yaml::from_str("max_body_bytes: 15").unwrap();
+        let fetched = module
+            .fetch(&format!("http://{}/", addr), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            fetched.data,
+            serde_json::json!({ "body": "012345678901234" })
+        );
+        assert_eq!(fetched.body_size, 1000);
+    }
+
+    #[tokio::test]
+    async fn metrics_source_modules_in_debug_mode() {
+        let config = r#"
+modules:
+  a:
+    jsonnet: "{}"
+    background: { target: "http://localhost" }
+  b:
+    jsonnet: "{}"
+    background: { target: "http://localhost" }
+"#;
+        let set_families = |app: &App| {
+            for module in &["a", "b"] {
+                set_background_families(
+                    app,
+                    module,
+                    vec![gauge_family("up", "Up.", &[("module", *module)], 1.0)],
+                );
+            }
+        };
+
+        let debug = app(&["--debug"], config);
+        set_families(&debug);
+        let output = body(debug.metrics_handler(None, None, None).await.unwrap()).await;
+        assert!(
+            output.contains("# module: a, b\n# HELP up Up.\n"),
+            "{}",
+            output
+        );
+
+        let plain = app(&[], config);
+        set_families(&plain);
+        let output = body(plain.metrics_handler(None, None, None).await.unwrap()).await;
+        assert!(!output.contains("# module:"), "{}", output);
+    }
 
-    HTTP_COUNTER.inc();
-    let timer = HTTP_REQ_HISTOGRAM.with_label_values(&["all"]).start_timer();
+    // streamed bodies are read within `block_in_place`, which needs a multi
+    // threaded runtime
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stream_large_array() {
+        let routes = warp::any().map(|| {
+            let elements = (0..2000).map(|id| {
+                let element = serde_json::json!({ "id": id, "code": ["200", "404"][id % 2] });
+                let separator = if id == 0 { "[" } else { "," };
+                Ok::<_, std::io::Error>(format!("{}{}", separator, element))
+            });
+            let chunks = elements.chain(std::iter::once(Ok("]".to_string())));
+            Response::new(hyper::Body::wrap_stream(futures::stream::iter(chunks)))
+        });
+        let (addr, server) = warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
 
-    let metric_families = prometheus::gather();
-    let mut buffer = vec![];
-    encoder.encode(&metric_families, &mut buffer).unwrap();
-    HTTP_BODY_GAUGE.set(buffer.len() as f64);
+        let app = app(
+            &[],
+            r#"
+modules:
+  stream:
+    stream_max_elements: 2000
+    max_body_bytes: 1000000
+    meta_metrics: true
+    jsonnet: |
+      { process(input):: {
+        requests_total: {
+          type: 'counter',
+          label_names: ['code'],
+          series: [{ label_values: [input.body.code], value: 1 }],
+        },
+        last_id: { type: 'gauge', series: [{ value: input.body.id }] },
+      } }
+"#,
+        );
+        let module = &app.config.modules["stream"];
+        let target = format!("http://{}/", addr);
+
+        let metric_families = app.probe("stream", module, &target).await.unwrap();
+        let output = encode_families(&merge_families(metric_families).unwrap()).unwrap();
+        for line in &[
+            "\nlast_id 1999\n",
+            "\nprobe_samples_scraped 4000\n",
+            "\nprobe_samples_post_filtering 3\n",
+            "\nrequests_total{code=\"200\"} 1000\nrequests_total{code=\"404\"} 1000\n",
+        ] {
+            assert!(output.contains(line), "{}", output);
+        }
+        assert!(output.contains("\nprobe_body_size_bytes "));
 
-    let response = Response::builder()
-        .status(200)
-        .header(CONTENT_TYPE, encoder.format_type())
-        .body(buffer)
+        let limited: ConfigModule = serde_yaml::from_str(
+            "{ stream_max_elements: 1000, jsonnet: '{ process(input):: {} }' }",
+        )
         .unwrap();
+        assert!(matches!(
+            app.probe("limited", &limited, &target).await,
+            Err(ProbeError::Eval(e)) if e.contains("more than 1000 elements")
+        ));
+    }
 
-    timer.observe_duration();
+    #[tokio::test]
+    async fn metrics_etag() {
+        let app = app(&[], BACKGROUND_CONFIG);
+        set_background_families(
+            &app,
+            "slow",
+            vec![gauge_family("slow_up", "slow", &[], 1.0)],
+        );
 
-    Ok(response)
-}
+        // the group excludes the self-metrics, which change with every request
+        let group = || Some("slow".to_string());
+        let response = app
+            .metrics_handler(group(), None, None)
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[CACHE_CONTROL], "no-cache");
+        let etag = response.headers()[ETAG].to_str().unwrap().to_string();
+        let length: usize = response.headers()[CONTENT_LENGTH]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(body(response).await.len(), length);
 
-struct Module {
-    path: Rc<PathBuf>,
-    state: EvaluationState,
-}
+        let response = app
+            .metrics_handler(group(), Some(etag.clone()), None)
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), 304);
+        assert_eq!(response.headers()[ETAG], etag.as_str());
+        assert_eq!(response.headers()[VARY], "Accept-Encoding");
 
-impl Module {
-    fn eval(&self, input: &String) -> Result<String> {
-        let eval = format!(
+        // the tag is weak, as the compressed body shares it
+        assert!(etag.starts_with("W/\""), "{}", etag);
+        let response = app
+            .metrics_handler(
+                group(),
+                Some(etag.trim_start_matches("W/").to_string()),
+                Some("gzip".into()),
+            )
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), 304);
+
+        let response = app
+            .metrics_handler(group(), Some("\"other\"".into()), None)
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), 200);
+    }
+
+    #[test]
+    fn sort_labels_by_label_order() {
+        let mut metric_families = vec![gauge_family(
+            "up",
+            "",
+            &[("zone", "a"), ("job", "b"), ("instance", "c"), ("app", "d")],
+            1.0,
+        )];
+        sort_labels(
+            &mut metric_families,
+            &["instance".to_string(), "job".to_string()],
+        );
+        let names: Vec<&str> = metric_families[0].get_metric()[0]
+            .get_label()
+            .iter()
+            .map(|l| l.get_name())
+            .collect();
+        // labels missing in the order follow alphabetically
+        assert_eq!(names, vec!["instance", "job", "app", "zone"]);
+    }
+
+    #[test]
+    fn bench_reports_stats() {
+        let app = app(
+            &[],
+            &r#"
+modules:
+  value:
+    jsonnet: "JSONNET"
+"#
+            .replace("JSONNET", VALUE_MODULE),
+        );
+        let report = app
+            .bench(&Bench {
+                module: "value".to_string(),
+                input: Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/value.json"),
+                iterations: 3,
+            })
+            .unwrap();
+        assert!(report.starts_with("iterations:  3\nmin:"), "{}", report);
+        assert!(report.contains("\navg:"), "{}", report);
+        assert_eq!(
+            report.contains("\nallocations:"),
+            cfg!(feature = "count-allocations"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn counter_reset_uses_clock() {
+        let module = module(
             r#"
-local s = import '{}';
+jsonnet: |
+  { process(input):: {
+    requests_total: {
+      type: 'counter',
+      label_names: ['code'],
+      series: [{ label_values: ['200'], value: 3, reset: true }],
+    },
+  } }
+"#,
+        );
+        let output =
+            encode_families(&module.eval_families(&serde_json::json!({})).unwrap()).unwrap();
+        assert!(
+            output.contains("\nrequests_created{code=\"200\"} 1600000000\n"),
+            "{}",
+            output
+        );
+    }
 
-s.process(std.extVar("input"))
+    #[test]
+    fn resolve_counters_created() {
+        let app = app(&["--clock", "10000"], "modules: {}");
+        let counter = || {
+            let mut mf = MetricFamily::default();
+            mf.set_name("requests_total".to_string());
+            mf.set_field_type(prometheus::proto::MetricType::COUNTER);
+            mf.mut_metric().push(prometheus::proto::Metric::default());
+            mf
+        };
+        let created_at = |metric_families: &[MetricFamily]| {
+            metric_families
+                .iter()
+                .find(|mf| mf.get_name() == "requests_created")
+                .unwrap()
+                .get_metric()[0]
+                .get_gauge()
+                .get_value()
+        };
+
+        // first seen
+        let mut metric_families = vec![counter()];
+        app.resolve_counters_created("m", "t", &mut metric_families);
+        assert_eq!(created_at(&metric_families), 10000.0);
+
+        // the module's creation time overwrites the remembered one
+        let mut metric_families = vec![counter(), gauge_family("requests_created", "", &[], 50.0)];
+        app.resolve_counters_created("m", "t", &mut metric_families);
+        assert_eq!(created_at(&metric_families), 50.0);
+        let mut metric_families = vec![counter()];
+        app.resolve_counters_created("m", "t", &mut metric_families);
+        assert_eq!(created_at(&metric_families), 50.0);
+
+        // targets not probed within the TTL are evicted
+        app.counters_created.lock().unwrap().insert(
+            ("m".to_string(), "stale".to_string()),
+            CountersCreated {
+                probed_at: 10000.0 - COUNTERS_CREATED_TTL,
+                series: HashMap::new(),
+            },
+        );
+        app.resolve_counters_created("m", "t", &mut vec![counter()]);
+        let counters_created = app.counters_created.lock().unwrap();
+        assert!(counters_created.contains_key(&("m".to_string(), "t".to_string())));
+        assert!(!counters_created.contains_key(&("m".to_string(), "stale".to_string())));
+    }
+
+    #[tokio::test]
+    async fn modules_and_index() {
+        let app = app(
+            &[],
+            r#"
+modules:
+  b:
+    jsonnet: "{}"
+  a:
+    description: "<b>CPU</b> & memory"
+    jsonnet: "{}"
 "#,
-            self.path.to_path_buf().to_str().expect("unpack string")
         );
 
-        self.state
-            .add_ext_code("input".into(), input.clone().into())
-            .map_err(|e| match e {
-                e => format!("err {:?}", e),
-            })?;
+        let modules: serde_json::Value =
+            serde_json::from_str(&body(app.modules_handler().await.unwrap()).await).unwrap();
+        assert_eq!(
+            modules,
+            serde_json::json!([
+                { "name": "a", "description": "<b>CPU</b> & memory" },
+                { "name": "b", "description": null },
+            ])
+        );
 
-        let path = Rc::new(PathBuf::from("eval.jsonnet"));
-        let result = self
-            .state
-            .evaluate_snippet_raw(path, eval.into())
-            .map_err(|e| match e {
-                e => format!("err {:?}", e),
-            })?;
-        info!("result = {:?}", result);
+        let index = body(app.index_handler().await.unwrap()).await;
+        assert!(
+            index.contains(
+                "<dt>a</dt><dd>&lt;b&gt;CPU&lt;/b&gt; &amp; memory</dd>\n<dt>b</dt><dd></dd>\n"
+            ),
+            "{}",
+            index
+        );
+    }
 
-        let manifest = self.state.manifest(result).map_err(|e| match e {
-            e => format!("err {:?}", e),
-        })?;
+    #[test]
+    fn load_config_base_dir() {
+        let config_file = Path::new(env!("CARGO_MANIFEST_DIR")).join("config.yaml");
+        let opts = |args: &[&str]| {
+            Opts::parse_from(
+                [
+                    "jsonnet-exporter",
+                    "--config-file",
+                    config_file.to_str().unwrap(),
+                ]
+                .iter()
+                .chain(args),
+            )
+        };
 
-        let metrics: Metrics = serde_json::from_str(&manifest)?;
+        let (config, _) = load_config(&opts(&[])).unwrap();
+        assert_eq!(config.base_dir, Path::new(env!("CARGO_MANIFEST_DIR")));
 
-        let registry = prometheus::Registry::new();
+        let (config, _) = load_config(&opts(&["--base-dir", "/srv/jsonnet"])).unwrap();
+        assert_eq!(config.base_dir, Path::new("/srv/jsonnet"));
+    }
 
-        for (metric_name, metric) in metrics.0 {
-            let m = match metric.r#type {
-                MetricType::Gauge => {
-                    let label_names = match &metric.label_names {
-                        Some(ln) => ln.iter().map(std::ops::Deref::deref).collect(),
-                        None => vec![],
-                    };
-                    let opts = prometheus::Opts::new(
-                        metric_name,
-                        match &metric.help {
-                            Some(help) => help,
-                        _ => "jsonnet-exporter: Metric help is missing, consider adding a help text to the module config.",
-                        },
-                    );
-                    let m = prometheus::GaugeVec::new(opts, &label_names)?;
-                    m
-                }
-            };
-            registry.register(Box::new(m.clone()))?;
+    #[test]
+    fn config_module_tests_pass() {
+        let config_file = Path::new(env!("CARGO_MANIFEST_DIR")).join("config.yaml");
+        let (config, _) = load_config(&Opts::parse_from(&[
+            "jsonnet-exporter",
+            "--config-file",
+            config_file.to_str().unwrap(),
+        ]))
+        .unwrap();
+        let parsed = ParseCache::default();
+        config.validate(&parsed).unwrap();
 
-            for s in &metric.series {
-                let label_values = match &s.label_values {
-                    Some(lv) => lv.iter().map(std::ops::Deref::deref).collect(),
-                    None => vec![],
-                };
-                m.with_label_values(&label_values).set(s.value);
+        for (name, module) in &config.modules {
+            let results = module
+                .run_tests(&config.base_dir, &config.jpath, &parsed)
+                .unwrap_or_else(|e| panic!("module {}: {}", name, e));
+            for result in results {
+                assert!(
+                    result.passed,
+                    "module {} test #{}:\n{}",
+                    name,
+                    result.test,
+                    result.diff.unwrap_or_default()
+                );
             }
         }
+    }
 
-        // Gather the metrics.
-        let mut buffer = vec![];
-        let encoder = prometheus::TextEncoder::new();
-        let metric_families = registry.gather();
-        encoder.encode(&metric_families, &mut buffer)?;
+    #[tokio::test]
+    async fn probe_module_info() {
+        let (addr, server) = warp::serve(warp::any().map(|| warp::reply::json(&"ok")))
+            .bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let app = app(
+            &[],
+            r#"
+modules:
+  info:
+    module_info: true
+    jsonnet: "{ process(input):: {} }"
+"#,
+        );
+        let version = app.config.modules["info"].version();
+        assert_eq!(version.len(), 12);
 
-        Ok(String::from_utf8(buffer).unwrap())
+        let mut params = HashMap::new();
+        params.insert("module".to_string(), "info".to_string());
+        params.insert("target".to_string(), format!("http://{}/", addr));
+        let output = body(app.probe_handler(params, HeaderMap::new()).await.unwrap()).await;
+        assert!(
+            output.contains(&format!(
+                "\nprobe_module_info{{module=\"info\",version=\"{}\"}} 1\n",
+                version
+            )),
+            "{}",
+            output
+        );
     }
-}
 
-struct App {
-    config: Config,
-    opts: Opts,
-}
+    #[test]
+    fn compress_gzip() {
+        let body = b"up 1\n".to_vec();
 
-impl App {
-    fn new() -> Self {
-        let opts: Opts = Opts::parse();
+        let (response, plain) = compress(Response::builder(), Some("deflate, br"), body.clone());
+        let response = response.body(()).unwrap();
+        assert_eq!(plain, body);
+        assert!(response.headers().get(CONTENT_ENCODING).is_none());
+        assert_eq!(response.headers()[VARY], "Accept-Encoding");
 
-        // Setup logger with default level info so we can see the messages from
-        // prometheus_exporter.
-        Builder::from_env(Env::default().default_filter_or("info")).init();
+        let (response, compressed) = compress(
+            Response::builder(),
+            Some("deflate, gzip;q=1.0"),
+            body.clone(),
+        );
+        let response = response.body(()).unwrap();
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+        let mut decompressed = vec![];
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+    }
 
-        // Parse config file
-        let config_file = File::open(&opts.config_file).expect("cannot open config file");
-        let config_reader = BufReader::new(config_file);
-        let config: Config =
-            serde_yaml::from_reader(config_reader).expect("cannot parse config file");
-        debug!("read config {:?}", config);
+    #[test]
+    fn credentials_refresh() {
+        let file = |name: &str| {
+            std::env::temp_dir().join(format!("jsonnet-exporter-{}-{}", std::process::id(), name))
+        };
+        let (cached, refreshed) = (file("cached"), file("refreshed"));
+        std::fs::write(&cached, "one\n").unwrap();
+        std::fs::write(&refreshed, "one\n").unwrap();
 
-        App {
-            config: config,
-            opts: opts,
-        }
+        let app = app(
+            &[],
+            &r#"
+modules:
+  cached:
+    credentials: { file: CACHED, prefix: "Bearer " }
+    jsonnet: "{}"
+  refreshed:
+    credentials: { file: REFRESHED, header: X-Token, refresh_seconds: 0 }
+    jsonnet: "{}"
+"#
+            .replace("CACHED", cached.to_str().unwrap())
+            .replace("REFRESHED", refreshed.to_str().unwrap()),
+        );
+        let credential = |name: &str| app.credential(&app.config.modules[name]).unwrap();
+
+        assert_eq!(
+            credential("cached"),
+            Some(("Authorization".to_string(), "Bearer one".to_string()))
+        );
+        assert_eq!(
+            credential("refreshed"),
+            Some(("X-Token".to_string(), "one".to_string()))
+        );
+
+        std::fs::write(&cached, "two\n").unwrap();
+        std::fs::write(&refreshed, "two\n").unwrap();
+        assert_eq!(
+            credential("cached"),
+            Some(("Authorization".to_string(), "Bearer one".to_string()))
+        );
+        assert_eq!(
+            credential("refreshed"),
+            Some(("X-Token".to_string(), "two".to_string()))
+        );
+
+        std::fs::remove_file(cached).unwrap();
+        std::fs::remove_file(refreshed).unwrap();
     }
-    async fn probe_handler(
-        &self,
-        params: HashMap<String, String>,
-    ) -> std::result::Result<impl Reply, Rejection> {
-        let module_name = match params.get("module") {
-            Some(module_name) => module_name,
-            None => {
-                return Err(warp::reject::custom(ProbeError::MissingParameter(
-                    "module".into(),
-                )));
-            }
+
+    /// Serves `/r/{n}`, which redirects to `/r/{n-1}` until `/r/0` returns
+    /// the received `Authorization` header, and `/cross`, which redirects
+    /// to `/r/0` on `localhost`.
+    fn serve_redirects() -> SocketAddr {
+        let json = |auth: Option<String>| {
+            Response::builder()
+                .header(CONTENT_TYPE, "application/json")
+                .body(serde_json::json!({ "authorization": auth }).to_string())
+                .unwrap()
         };
+        let redirect = |location: String| {
+            Response::builder()
+                .status(302)
+                .header(LOCATION, location)
+                .body(String::new())
+                .unwrap()
+        };
+        let hops = warp::path!("r" / usize)
+            .and(warp::header::optional::<String>("authorization"))
+            .map(move |n: usize, auth| match n {
+                0 => json(auth),
+                n => redirect(format!("/r/{}", n - 1)),
+            });
+        let cross = warp::path!("cross")
+            .and(warp::header::<String>("host"))
+            .map(move |host: String| {
+                redirect(format!(
+                    "http://{}/r/0",
+                    host.replace("127.0.0.1", "localhost")
+                ))
+            });
+        let (addr, server) = warp::serve(hops.or(cross)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        addr
+    }
 
-        let module = match self.config.modules.get(module_name) {
-            Some(m) => m,
-            None => {
-                return Err(warp::reject::custom(ProbeError::ModuleNotFound(
-                    module_name.clone(),
-                )))
+    #[tokio::test]
+    async fn fetch_redirects() {
+        let addr = serve_redirects();
+        let config = |config: &str| serde_yaml::from_str::<ConfigModule>(config).unwrap();
+        let credential = Some(("Authorization".to_string(), "secret".to_string()));
+        let url = |path: &str| format!("http://{}{}", addr, path);
+
+        let limited = config("max_redirects: 2");
+        let fetched = limited
+            .fetch(&url("/r/2"), credential.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            fetched.data,
+            serde_json::json!({ "body": { "authorization": "secret" } })
+        );
+        match limited.fetch(&url("/r/3"), None).await {
+            Err(ProbeError::TooManyRedirects(2)) => {}
+            other => panic!("unexpected result {:?}", other.map(|f| f.data)),
+        }
+
+        match limited.fetch(&url("/cross"), None).await {
+            Err(ProbeError::Redirect(e)) => {
+                assert_eq!(e, "redirect to other host 'localhost' is not allowed")
             }
+            other => panic!("unexpected result {:?}", other.map(|f| f.data)),
+        }
+
+        // the credential isn't sent to the other host
+        let cross_host = config("{ max_redirects: 1, follow_cross_host: true }");
+        let fetched = cross_host.fetch(&url("/cross"), credential).await.unwrap();
+        assert_eq!(
+            fetched.data,
+            serde_json::json!({ "body": { "authorization": null } })
+        );
+    }
+
+    #[tokio::test]
+    async fn fetch_redirect_to_plain_http() {
+        let http = serve_redirects();
+        let https = serve_tls("localhost.p12").await;
+        let module: ConfigModule = serde_yaml::from_str("max_redirects: 1").unwrap();
+        let credential = Some(("Authorization".to_string(), "secret".to_string()));
+
+        // the host stays the same, but the credential isn't sent unencrypted
+        let fetched = module
+            .fetch(&format!("https://{}/http/{}/r/0", https, http), credential)
+            .await
+            .unwrap();
+        assert_eq!(
+            fetched.data,
+            serde_json::json!({ "body": { "authorization": null } })
+        );
+    }
+
+    #[test]
+    fn same_origin_compares_scheme_host_and_port() {
+        let uri = |uri: &str| uri.parse::<hyper::Uri>().unwrap();
+        assert!(same_origin(
+            &uri("https://host/a"),
+            &uri("https://host:443/b")
+        ));
+        assert!(!same_origin(
+            &uri("https://host:8080/"),
+            &uri("http://host:8080/")
+        ));
+        assert!(!same_origin(
+            &uri("http://host:8080/"),
+            &uri("http://host:8081/")
+        ));
+        assert!(!same_origin(&uri("http://host/"), &uri("http://other/")));
+    }
+
+    #[test]
+    fn jsonnet_test_input() {
+        let jsonnet = r#"
+jsonnet: |
+  { process(input):: {
+    value: { type: 'gauge', help: 'Value.', series: [{ value: input.body.value }] },
+  } }
+"#;
+        let state = module(jsonnet);
+        let json_input = state
+            .eval_input(r#"{"body": {"value": 2, "name": "a"}}"#)
+            .unwrap();
+        let jsonnet_input = state
+            .eval_input("{ body: { value: 1 + 1, name: 'a' } }")
+            .unwrap();
+        assert_eq!(json_input, jsonnet_input);
+        assert_eq!(
+            json_input,
+            serde_json::json!({ "body": { "value": 2, "name": "a" } })
+        );
+        assert_eq!(
+            encode_families(&state.eval_families(&jsonnet_input).unwrap()).unwrap(),
+            "# HELP value Value.\n# TYPE value gauge\nvalue 2\n"
+        );
+
+        let module: ConfigModule = serde_yaml::from_str(&format!(
+            r##"{}
+tests:
+  - input: '{{"body": {{"value": 2}}}}'
+    output: "# HELP value Value.\n# TYPE value gauge\nvalue 2\n"
+  - input: "{{ body: {{ value: 1 + 1 }} }}"
+    output: "# HELP value Value.\n# TYPE value gauge\nvalue 2\n"
+"##,
+            jsonnet
+        ))
+        .unwrap();
+        let results = module
+            .run_tests(
+                Path::new(env!("CARGO_MANIFEST_DIR")),
+                &[],
+                &ParseCache::default(),
+            )
+            .unwrap();
+        assert!(results.iter().all(|r| r.passed));
+        assert_eq!(results.len(), 2);
+    }
+
+    #[test]
+    fn json_depth() {
+        assert!(!json_depth_exceeds(br#"{"a": [1, {"b": 2}]}"#, 3));
+        assert!(json_depth_exceeds(br#"{"a": [1, {"b": [2]}]}"#, 3));
+        // brackets within strings don't nest
+        assert!(!json_depth_exceeds(br#"{"a": "[[[{\"{{"}"#, 1));
+
+        // the state carries over between chunks
+        let mut depth = JsonDepth::default();
+        assert!(!depth.exceeds(br#"[{"a": "\"#, 2));
+        assert!(!depth.exceeds(br#"["}, "#, 2));
+        assert!(depth.exceeds(br#"[["#, 2));
+    }
+
+    #[tokio::test]
+    async fn tests_endpoint() {
+        let config = r##"
+modules:
+  value:
+    jsonnet: |
+      { process(input):: {
+        value: { type: 'gauge', help: 'Value.', series: [{ value: input.body.value }] },
+      } }
+    tests:
+      - input: '{"body": {"value": 2}}'
+        output: "# HELP value Value.\n# TYPE value gauge\nvalue 2\n"
+      - input: '{"body": {"value": 3}}'
+        output: "# HELP value Value.\n# TYPE value gauge\nvalue 2\n"
+"##;
+
+        let disabled: &'static App = Box::leak(Box::new(app(&[], config)));
+        let rejection = disabled.tests_handler().await.err().unwrap();
+        assert!(rejection.is_not_found());
+
+        let debug: &'static App = Box::leak(Box::new(app(&["--debug"], config)));
+        let reports: serde_json::Value =
+            serde_json::from_str(&body(debug.tests_handler().await.unwrap()).await).unwrap();
+        assert_eq!(reports[0]["module"], "value");
+        assert_eq!(reports[0]["error"], serde_json::Value::Null);
+        assert_eq!(reports[0]["tests"][0]["passed"], true);
+        assert_eq!(reports[0]["tests"][1]["passed"], false);
+    }
+
+    #[test]
+    fn diff_output_plain() {
+        assert_eq!(diff_output("a\nb\n", "a\nb\n"), None);
+        let diff = diff_output("a\nb\nc\nd\n", "a\nx\nc\n").unwrap();
+        assert_eq!(diff, " a\n-x\n+b\n c\n+d\n \n");
+        assert!(!diff.contains('\x1b'), "{:?}", diff);
+    }
+
+    #[test]
+    fn undefined_ext_var() {
+        let module = module(r#"jsonnet: "{ process(input):: { x: std.extVar('target') } }""#);
+        let error = module
+            .eval_families(&serde_json::json!({}))
+            .err()
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            error,
+            "module referenced undefined ext var 'target'; available: [input]"
+        );
+    }
+
+    #[tokio::test]
+    async fn config_checksum_metric() {
+        let config_file = std::env::temp_dir().join(format!(
+            "jsonnet-exporter-{}-config.yaml",
+            std::process::id()
+        ));
+        std::fs::write(&config_file, "modules: {}\n").unwrap();
+        let opts = Opts::parse_from(&[
+            "jsonnet-exporter",
+            "--config-checksum-metric",
+            "--config-file",
+            config_file.to_str().unwrap(),
+        ]);
+        let (config, config_sha256) = load_config(&opts).unwrap();
+        std::fs::remove_file(&config_file).unwrap();
+        assert_eq!(
+            config_sha256,
+            format!("{:x}", Sha256::digest(b"modules: {}\n"))
+        );
+
+        let app = App::with_config(opts, config, config_sha256.clone());
+        let output = body(app.metrics_handler(None, None, None).await.unwrap()).await;
+        assert!(
+            output.contains(&format!(
+                "\njsonnet_exporter_config_file_sha256{{sha256=\"{}\"}} 1\n",
+                config_sha256
+            )),
+            "{}",
+            output
+        );
+
+        // without namespace the name carries no leading underscore
+        let app = App::with_config(
+            Opts::parse_from(&[
+                "jsonnet-exporter",
+                "--config-checksum-metric",
+                "--metrics-namespace",
+                "",
+            ]),
+            serde_yaml::from_str("modules: {}").unwrap(),
+            config_sha256.clone(),
+        );
+        let output = body(app.metrics_handler(None, None, None).await.unwrap()).await;
+        assert!(
+            output.contains(&format!(
+                "\nconfig_file_sha256{{sha256=\"{}\"}} 1\n",
+                config_sha256
+            )),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn allowed_metrics() {
+        let module = module(
+            r#"
+allowed_metrics: [value]
+jsonnet: |
+  { process(input):: {
+    value: { type: 'gauge', series: [{ value: 1 }] },
+    other: { type: 'gauge', series: [{ value: 2 }] },
+  } }
+"#,
+        );
+        let error = module
+            .eval_families(&serde_json::json!({}))
+            .err()
+            .unwrap()
+            .to_string();
+        assert_eq!(error, "metric 'other' is not in allowed_metrics");
+    }
+
+    #[test]
+    fn eval_fallback() {
+        let app = app(
+            &[],
+            r#"
+modules:
+  primary:
+    fallback: degraded
+    jsonnet: "{ process(input):: error 'broken' }"
+  degraded:
+    jsonnet: |
+      { process(input):: {
+        value: { type: 'gauge', series: [{ value: input.body.value }] },
+        failed: {
+          type: 'gauge',
+          label_names: ['broken'],
+          series: [{ label_values: [std.toString(std.length(std.findSubstr('broken', input.error)) > 0)], value: 1 }],
+        },
+      } }
+"#,
+        );
+        let primary = &app.config.modules["primary"];
+        let mut input = fetched(serde_json::json!({ "body": { "value": 2 } }));
+
+        let error = match app.eval_module("primary", primary, &mut input) {
+            Err(ProbeError::Eval(e)) => e,
+            other => panic!("unexpected result {:?}", other),
         };
+        let metric_families = app
+            .eval_fallback("primary", primary, &input, error)
+            .unwrap();
+        let output = encode_families(&metric_families).unwrap();
+        assert!(output.contains("\nvalue 2\n"), "{}", output);
+        assert!(
+            output.contains("\nfailed{broken=\"true\"} 1\n"),
+            "{}",
+            output
+        );
+    }
 
-        let target = match params.get("target") {
-            Some(target) => target,
-            None => {
-                return Err(warp::reject::custom(ProbeError::MissingParameter(
-                    "target".into(),
-                )));
-            }
+    #[test]
+    fn exemplar_label_length() {
+        let module = module(&format!(
+            r#"
+jsonnet: |
+  {{ process(input):: {{
+    requests_total: {{
+      type: 'counter',
+      series: [{{ value: 1, exemplar: {{ value: 1, labels: {{ trace_id: '{}' }} }} }}],
+    }},
+  }} }}
+"#,
+            "a".repeat(121)
+        ));
+        let error = module
+            .eval_families(&serde_json::json!({}))
+            .err()
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            error,
+            "metric 'requests_total' exemplar labels have 129 characters, more than 128"
+        );
+    }
+
+    #[test]
+    fn test_modules_in_parallel() {
+        let config = |output: &str| {
+            r##"
+modules:
+  a:
+    jsonnet: "{ process(input):: { a: { type: 'gauge', help: 'A.', series: [{ value: 1 }] } } }"
+    tests: [{ input: '{"body": {}}', output: "# HELP a A.\n# TYPE a gauge\na 1\n" }]
+  b:
+    jsonnet: "{ process(input):: { b: { type: 'gauge', help: 'B.', series: [{ value: 1 }] } } }"
+    tests: [{ input: '{"body": {}}', output: "OUTPUT" }]
+  c:
+    jsonnet: "{ process(input):: error 'broken' }"
+"##
+            .replace("OUTPUT", output)
         };
 
-        let uri = target
-            .parse()
-            .map_err(|e| ProbeError::InvalidTargetUrl(e))?;
+        let passing: &'static App = Box::leak(Box::new(app(
+            &[],
+            &config("# HELP b B.\\n# TYPE b gauge\\nb 1\\n"),
+        )));
+        assert!(passing.test(&Test { concurrency: 2 }));
 
-        // Await the response...
-        use hyper::Client;
-        use hyper_tls::HttpsConnector;
-        let https = HttpsConnector::new();
-        let client = Client::builder().build::<_, hyper::Body>(https);
-        let resp = client
-            .get(uri)
+        let failing: &'static App = Box::leak(Box::new(app(&[], &config("b 2\\n"))));
+        assert!(!failing.test(&Test { concurrency: 2 }));
+
+        // the results don't depend on the number of threads
+        let sequential = failing.test_reports(1);
+        assert_eq!(sequential.len(), 3);
+        assert_eq!(failing.test_reports(3), sequential);
+    }
+
+    #[tokio::test]
+    async fn content_type_override() {
+        let (addr, server) =
+            warp::serve(warp::any().map(|| "value: 2\n")).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let app = app(
+            &[],
+            &r#"
+modules:
+  value:
+    jsonnet: "JSONNET"
+"#
+            .replace("JSONNET", VALUE_MODULE),
+        );
+
+        let output = app
+            .eval(&Eval {
+                module: "value".to_string(),
+                input: Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/value.yaml"),
+                content_type: ContentType::Yaml,
+            })
+            .unwrap();
+        assert!(output.contains("\nvalue 1\n"), "{}", output);
+
+        let output = app
+            .run_once(&RunOnce {
+                module: "value".to_string(),
+                target: Some(format!("http://{}/", addr)),
+                content_type: Some(ContentType::Yaml),
+            })
             .await
-            .map_err(|e| ProbeError::TargetHTTP(e))?;
-        let headers = &resp.headers().clone();
+            .unwrap();
+        assert!(output.contains("\nvalue 2\n"), "{}", output);
 
-        let body = hyper::body::aggregate(resp)
+        // served as text/plain, the body is passed as string
+        let error = app
+            .run_once(&RunOnce {
+                module: "value".to_string(),
+                target: Some(format!("http://{}/", addr)),
+                content_type: None,
+            })
             .await
-            .map_err(|e| ProbeError::TargetHTTP(e))?;
+            .err()
+            .unwrap();
+        assert!(
+            error.to_string().starts_with("probe failed: Eval("),
+            "{}",
+            error
+        );
+    }
 
-        let json_body: serde_json::Value = match headers.get(CONTENT_TYPE) {
-            Some(header_value) if header_value == HeaderValue::from_static("application/json") => {
-                info!("json response");
-                serde_json::from_reader(body.reader())
-                    .map_err(|e| ProbeError::TargetJSONParse(e))?
-            }
-            _ => {
-                info!("string response");
-                let mut buffer = String::new();
-                body.reader().read_to_string(&mut buffer).unwrap();
-                serde_json::Value::String(buffer)
+    #[tokio::test]
+    async fn stream_metrics() {
+        let buffered = app(&[], BACKGROUND_CONFIG);
+        let streamed = app(&["--stream-metrics"], BACKGROUND_CONFIG);
+        for exporter in &[&buffered, &streamed] {
+            set_background_families(
+                exporter,
+                "slow",
+                vec![
+                    gauge_family("slow_up", "slow", &[], 1.0),
+                    gauge_family("slow_items", "items", &[("queue", "a")], 3.0),
+                ],
+            );
+        }
+        let group = || Some("slow".to_string());
+
+        let response = streamed
+            .metrics_handler(group(), Some("*".into()), None)
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().get(ETAG).is_none());
+        assert!(response.headers().get(CONTENT_LENGTH).is_none());
+        let output = body(response).await;
+
+        let expected = body(buffered.metrics_handler(group(), None, None).await.unwrap()).await;
+        assert_eq!(output, expected);
+    }
+
+    #[tokio::test]
+    async fn reject_private_targets() {
+        for (ip, private) in &[
+            ("127.0.0.1", true),
+            ("10.1.2.3", true),
+            ("100.64.0.1", true),
+            ("169.254.169.254", true),
+            ("93.184.216.34", false),
+            ("::1", true),
+            ("fd00::1", true),
+            ("::ffff:192.168.0.1", true),
+            ("2606:2800:220:1::", false),
+        ] {
+            assert_eq!(is_private_address(&ip.parse().unwrap()), *private, "{}", ip);
+        }
+
+        let config = |config: &str| serde_yaml::from_str::<ConfigModule>(config).unwrap();
+        let uri = |uri: &str| uri.parse::<hyper::Uri>().unwrap();
+        let rejecting = config("reject_private_targets: true");
+        match rejecting
+            .check_target_address(&uri("http://127.0.0.1:8080/"))
+            .await
+        {
+            Err(ProbeError::PrivateTarget(e)) => {
+                assert_eq!(e, "'127.0.0.1' resolves to private address 127.0.0.1")
             }
-        };
+            other => panic!("unexpected result {:?}", other),
+        }
+        rejecting
+            .check_target_address(&uri("http://93.184.216.34/"))
+            .await
+            .unwrap();
+
+        let allowing =
+            config("{ reject_private_targets: true, allowed_private_hosts: [127.0.0.1] }");
+        allowing
+            .check_target_address(&uri("http://127.0.0.1:8080/"))
+            .await
+            .unwrap();
 
-        let data = serde_json::to_string(&InputData { body: json_body }).unwrap();
+        // hosts are checked again as the connection resolves them
+        let client = hyper::Client::builder()
+            .build::<_, hyper::Body>(CertificateConnector::new(rejecting.resolver()));
+        let target = uri("http://localhost:8080/");
+        let e = client.get(target.clone()).await.err().unwrap();
+        match rejecting.http_error(&target, e).await {
+            ProbeError::PrivateTarget(e) => assert!(
+                e.starts_with("'localhost' resolves to private address"),
+                "{}",
+                e
+            ),
+            other => panic!("unexpected error {:?}", other),
+        }
+        match rejecting
+            .unverified_certificate(&uri("https://localhost:8443/"))
+            .await
+        {
+            Err(ProbeError::PrivateTarget(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
 
-        info!("{:?}", data);
+    #[test]
+    fn debug_manifest_in_error() {
+        let config = r#"
+modules:
+  broken:
+    jsonnet: "{ process(input):: { value: { type: 'gauge', help: input.body.token, series: 'x' } } }"
+"#;
+        let eval_error = |app: &App| {
+            let module = &app.config.modules["broken"];
+            let mut input = fetched(serde_json::json!({ "body": { "token": "secret" } }));
+            match app.eval_module("broken", module, &mut input) {
+                Err(ProbeError::Eval(e)) => e,
+                other => panic!("unexpected result {:?}", other),
+            }
+        };
 
-        let metrics = module.state().unwrap().eval(&data).unwrap();
+        let plain = eval_error(&app(&[], config));
+        assert!(!plain.contains("secret"), "{}", plain);
 
-        Ok(metrics)
+        let debug = eval_error(&app(&["--debug"], config));
+        assert!(debug.contains("\nmanifest:\n"), "{}", debug);
+        assert!(debug.contains("\"secret\""), "{}", debug);
     }
-}
 
-lazy_static! {
-    static ref APP: App = App::new();
-}
+    #[test]
+    fn eval_cache() {
+        let app = app(
+            &[],
+            &r#"
+modules:
+  eval_cache:
+    eval_cache_size: 1
+    jsonnet: "JSONNET"
+"#
+            .replace("JSONNET", VALUE_MODULE),
+        );
+        let module = &app.config.modules["eval_cache"];
+        // the counter is shared by all tests, the module name is unique
+        let evaluations = || EVALUATIONS.with_label_values(&["eval_cache"]).get();
+        let eval = |value: f64| {
+            let mut input = fetched(serde_json::json!({ "body": { "value": value } }));
+            encode_families(&app.eval_module("eval_cache", module, &mut input).unwrap()).unwrap()
+        };
 
-#[tokio::main]
-async fn main() {
-    APP.config.validate().expect("cannot validate config file");
+        assert!(eval(1.0).contains("\nvalue 1\n"));
+        assert!(eval(1.0).contains("\nvalue 1\n"));
+        assert_eq!(evaluations(), 1.0);
 
-    // GET /hello/warp => 200 OK with body "Hello, warp!"
-    let hello = warp::path!("hello" / String).map(|name| format!("Hello, {}!", name));
+        assert!(eval(2.0).contains("\nvalue 2\n"));
+        assert_eq!(evaluations(), 2.0);
+
+        // the first input has been evicted
+        assert!(eval(1.0).contains("\nvalue 1\n"));
+        assert_eq!(evaluations(), 3.0);
+    }
 
-    let metrics = warp::path!("metrics").and_then(metrics_handler);
+    #[tokio::test]
+    async fn metrics_per_module() {
+        let per_module = app(&["--metrics-per-module"], BACKGROUND_CONFIG);
+        set_background_families(
+            &per_module,
+            "fast",
+            vec![gauge_family("fast_up", "fast", &[], 1.0)],
+        );
+        set_background_families(
+            &per_module,
+            "slow",
+            vec![gauge_family("slow_up", "slow", &[], 1.0)],
+        );
 
-    let probe = warp::path!("probe")
-        .and(warp::query::<HashMap<String, String>>())
-        .and_then(|p| APP.probe_handler(p));
+        let output = body(
+            per_module
+                .metrics_handler(Some("fast".into()), None, None)
+                .await
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(
+            output,
+            "# HELP fast_up fast\n# TYPE fast_up gauge\nfast_up 1\n"
+        );
 
-    let routes = warp::get().and(hello.or(metrics).or(probe));
-    // Parse address used to bind exporter to.
-    let addr: SocketAddr = APP
-        .opts
-        .bind_addr
-        .parse()
-        .expect("can not parse listen addr");
+        let rejection = per_module
+            .metrics_handler(Some("missing".into()), None, None)
+            .await
+            .err()
+            .unwrap();
+        assert!(rejection.is_not_found());
 
-    warp::serve(routes).run(addr).await;
+        let disabled = app(&[], BACKGROUND_CONFIG);
+        let rejection = disabled
+            .metrics_handler(Some("fast".into()), None, None)
+            .await
+            .err()
+            .unwrap();
+        assert!(rejection.is_not_found());
+    }
 }
@@ -0,0 +1,494 @@
+use std::convert::TryFrom;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, TimeZone, Utc};
+use log::debug;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use jrsonnet_evaluator::{
+    error::{Error::RuntimeError, Result},
+    native::NativeCallback,
+    throw, EvaluationState, Val,
+};
+use jrsonnet_parser::{Expr, LiteralType, LocExpr, Param, ParamsDesc};
+
+use regex::Regex;
+
+fn params(names: &[&str]) -> ParamsDesc {
+    ParamsDesc(Rc::new(
+        names
+            .iter()
+            .map(|name| Param((*name).into(), None))
+            .collect(),
+    ))
+}
+
+/// Like `params`, followed by `optional` parameters defaulting to `false`.
+fn params_default_false(names: &[&str], optional: &[&str]) -> ParamsDesc {
+    let default = LocExpr(Rc::new(Expr::Literal(LiteralType::False)), None);
+    ParamsDesc(Rc::new(
+        names
+            .iter()
+            .map(|name| Param((*name).into(), None))
+            .chain(
+                optional
+                    .iter()
+                    .map(|name| Param((*name).into(), Some(default.clone()))),
+            )
+            .collect(),
+    ))
+}
+
+/// Source of the time returned by the `now()` native.
+#[derive(Clone, Copy, Debug)]
+pub enum Clock {
+    System,
+    /// A fixed unix timestamp, used to make tests reproducible.
+    Fixed(f64),
+}
+
+impl Clock {
+    pub fn now(&self) -> f64 {
+        match self {
+            Clock::System => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or(0.0),
+            Clock::Fixed(now) => *now,
+        }
+    }
+}
+
+/// Registers the native functions available to modules using
+/// `std.native(name)`.
+pub fn add_natives(state: &EvaluationState, clock: Clock) {
+    state.add_native(
+        "regexMatch".into(),
+        Rc::new(NativeCallback::new(
+            params(&["regex", "string"]),
+            |_caller, args| regex_match(&args[0], &args[1]),
+        )),
+    );
+    state.add_native(
+        "hash".into(),
+        Rc::new(NativeCallback::new(
+            params(&["algo", "string"]),
+            |_caller, args| hash(&args[0], &args[1]),
+        )),
+    );
+    state.add_native(
+        "parseUrl".into(),
+        Rc::new(NativeCallback::new(params(&["url"]), |_caller, args| {
+            parse_url(&args[0])
+        })),
+    );
+    state.add_native(
+        "now".into(),
+        Rc::new(NativeCallback::new(params(&[]), move |_caller, _args| {
+            Ok(Val::Num(clock.now()))
+        })),
+    );
+    state.add_native(
+        "clamp".into(),
+        Rc::new(NativeCallback::new(
+            params(&["value", "min", "max"]),
+            |_caller, args| clamp(&args[0], &args[1], &args[2]),
+        )),
+    );
+    state.add_native(
+        "parseInt".into(),
+        Rc::new(NativeCallback::new(
+            params(&["string", "radix"]),
+            |_caller, args| parse_int(&args[0], &args[1]),
+        )),
+    );
+    state.add_native(
+        "deepMerge".into(),
+        Rc::new(NativeCallback::new(
+            params_default_false(&["a", "b"], &["concatArrays"]),
+            |_caller, args| deep_merge(&args[0], &args[1], &args[2]),
+        )),
+    );
+    state.add_native(
+        "parseTime".into(),
+        Rc::new(NativeCallback::new(
+            params(&["rfc3339"]),
+            |_caller, args| parse_time(&args[0]),
+        )),
+    );
+    state.add_native(
+        "formatTime".into(),
+        Rc::new(NativeCallback::new(
+            params(&["seconds", "fmt"]),
+            |_caller, args| format_time(&args[0], &args[1]),
+        )),
+    );
+    state.add_native(
+        "sanitizeLabel".into(),
+        Rc::new(NativeCallback::new(params(&["string"]), |_caller, args| {
+            sanitize_label(&args[0])
+        })),
+    );
+    state.add_native(
+        "stats".into(),
+        Rc::new(NativeCallback::new(params(&["array"]), |_caller, args| {
+            stats(&args[0])
+        })),
+    );
+    state.add_native(
+        "percentile".into(),
+        Rc::new(NativeCallback::new(
+            params(&["array", "p"]),
+            |_caller, args| percentile(&args[0], &args[1]),
+        )),
+    );
+}
+
+fn regex_match(regex: &Val, string: &Val) -> Result<Val> {
+    match (regex, string) {
+        (Val::Str(regex), Val::Str(string)) => {
+            let re = Regex::new(regex).unwrap();
+
+            let matches: Vec<Val> = re
+                .captures_iter(string)
+                .map(|capture| {
+                    let val: Vec<Val> = capture
+                        .iter()
+                        .filter_map(|submatch| match submatch {
+                            Some(m) => Some(Val::Str(m.as_str().into())),
+                            None => Some(Val::Null),
+                        })
+                        .collect();
+                    Val::Arr(val.into())
+                })
+                .collect();
+
+            debug!("native call regexMatch={:?}", matches);
+
+            Ok(Val::Arr(matches.into()))
+        }
+        (_, _) => unreachable!(),
+    }
+}
+
+/// Returns the hex digest of a string, supported algorithms are `sha256` and
+/// `md5`.
+fn hash(algo: &Val, string: &Val) -> Result<Val> {
+    match (algo, string) {
+        (Val::Str(algo), Val::Str(string)) => {
+            let digest = match &**algo {
+                "sha256" => format!("{:x}", Sha256::digest(string.as_bytes())),
+                "md5" => format!("{:x}", Md5::digest(string.as_bytes())),
+                _ => throw!(RuntimeError(
+                    format!("hash: unsupported algorithm '{}'", algo).into()
+                )),
+            };
+            Ok(Val::Str(digest.into()))
+        }
+        (_, _) => throw!(RuntimeError("hash: expected algo and string".into())),
+    }
+}
+
+/// Splits an URL into an object of `scheme`, `host`, `port`, `path`, `query`
+/// and the decoded `params` of the query.
+fn parse_url(url: &Val) -> Result<Val> {
+    let url = match url {
+        Val::Str(url) => url,
+        _ => throw!(RuntimeError("parseUrl: expected string".into())),
+    };
+    let parsed = match url::Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(e) => throw!(RuntimeError(
+            format!("parseUrl: invalid url '{}': {}", url, e).into()
+        )),
+    };
+
+    let params: serde_json::Map<String, serde_json::Value> = parsed
+        .query_pairs()
+        .map(|(k, v)| (k.into_owned(), serde_json::Value::String(v.into_owned())))
+        .collect();
+
+    Ok(Val::from(&serde_json::json!({
+        "scheme": parsed.scheme(),
+        "host": parsed.host_str(),
+        "port": parsed.port_or_known_default(),
+        "path": parsed.path(),
+        "query": parsed.query(),
+        "params": params,
+    })))
+}
+
+/// Bounds a number to the range `[min, max]`.
+fn clamp(value: &Val, min: &Val, max: &Val) -> Result<Val> {
+    match (value, min, max) {
+        (Val::Num(value), Val::Num(min), Val::Num(max)) => {
+            if min > max {
+                throw!(RuntimeError(
+                    format!("clamp: min {} is greater than max {}", min, max).into()
+                ));
+            }
+            Ok(Val::Num(value.max(*min).min(*max)))
+        }
+        (_, _, _) => throw!(RuntimeError("clamp: expected numbers".into())),
+    }
+}
+
+/// Parses an integer in the given radix, a `0x`, `0o` or `0b` prefix matching
+/// the radix is accepted.
+fn parse_int(string: &Val, radix: &Val) -> Result<Val> {
+    let (string, radix) = match (string, radix) {
+        (Val::Str(string), Val::Num(radix)) => (string, *radix),
+        (_, _) => throw!(RuntimeError("parseInt: expected string and radix".into())),
+    };
+    if radix.fract() != 0.0 || radix < 2.0 || radix > 36.0 {
+        throw!(RuntimeError(
+            format!("parseInt: invalid radix {}", radix).into()
+        ));
+    }
+    let radix = radix as u32;
+
+    let (negative, digits) = match string.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, &string[..]),
+    };
+    let prefix = match radix {
+        16 => Some("0x"),
+        8 => Some("0o"),
+        2 => Some("0b"),
+        _ => None,
+    };
+    let digits = match prefix {
+        Some(prefix) if digits.to_lowercase().starts_with(prefix) => &digits[2..],
+        _ => digits,
+    };
+    // `from_str_radix` accepts a sign, which would allow a second one
+    if digits.starts_with(|c| c == '+' || c == '-') {
+        throw!(RuntimeError(
+            format!("parseInt: invalid number '{}' in radix {}", string, radix).into()
+        ));
+    }
+
+    match i64::from_str_radix(digits, radix) {
+        Ok(value) if negative => Ok(Val::Num(-value as f64)),
+        Ok(value) => Ok(Val::Num(value as f64)),
+        Err(e) => throw!(RuntimeError(
+            format!(
+                "parseInt: invalid number '{}' in radix {}: {}",
+                string, radix, e
+            )
+            .into()
+        )),
+    }
+}
+
+/// Recursively merges object `b` into object `a`. Fields present in both
+/// objects are merged when both values are objects, arrays are concatenated
+/// when `concatArrays` is true (default false), otherwise the value of `b`
+/// wins, including `null`.
+fn deep_merge(a: &Val, b: &Val, concat_arrays: &Val) -> Result<Val> {
+    let concat_arrays = match concat_arrays {
+        Val::Bool(concat_arrays) => *concat_arrays,
+        _ => throw!(RuntimeError(
+            "deepMerge: expected boolean concatArrays".into()
+        )),
+    };
+    let mut a = serde_json::Value::try_from(a)?;
+    let b = serde_json::Value::try_from(b)?;
+    merge_values(&mut a, b, concat_arrays);
+    Ok(Val::from(&a))
+}
+
+fn merge_values(a: &mut serde_json::Value, b: serde_json::Value, concat_arrays: bool) {
+    use serde_json::Value;
+
+    match (a, b) {
+        (Value::Object(a), Value::Object(b)) => {
+            for (key, value) in b {
+                match a.get_mut(&key) {
+                    Some(existing) => merge_values(existing, value, concat_arrays),
+                    None => {
+                        a.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Array(a), Value::Array(b)) if concat_arrays => a.extend(b),
+        (a, b) => *a = b,
+    }
+}
+
+/// Parses a RFC 3339 timestamp into unix seconds.
+fn parse_time(rfc3339: &Val) -> Result<Val> {
+    let rfc3339 = match rfc3339 {
+        Val::Str(rfc3339) => rfc3339,
+        _ => throw!(RuntimeError("parseTime: expected string".into())),
+    };
+    match DateTime::parse_from_rfc3339(rfc3339) {
+        Ok(time) => Ok(Val::Num(
+            time.timestamp() as f64 + time.timestamp_subsec_nanos() as f64 / 1e9,
+        )),
+        Err(e) => throw!(RuntimeError(
+            format!("parseTime: invalid timestamp '{}': {}", rfc3339, e).into()
+        )),
+    }
+}
+
+/// Formats unix seconds in UTC using a strftime format, like
+/// `%Y-%m-%dT%H:%M:%SZ`.
+fn format_time(seconds: &Val, fmt: &Val) -> Result<Val> {
+    let (seconds, fmt) = match (seconds, fmt) {
+        (Val::Num(seconds), Val::Str(fmt)) => (*seconds, fmt),
+        (_, _) => throw!(RuntimeError(
+            "formatTime: expected seconds and format".into()
+        )),
+    };
+
+    let nanos = (seconds.fract().abs() * 1e9) as u32;
+    let time = match Utc.timestamp_opt(seconds.trunc() as i64, nanos).single() {
+        Some(time) => time,
+        None => throw!(RuntimeError(
+            format!("formatTime: invalid timestamp {}", seconds).into()
+        )),
+    };
+
+    let items: Vec<Item> = StrftimeItems::new(fmt).collect();
+    if items.iter().any(|item| matches!(item, Item::Error)) {
+        throw!(RuntimeError(
+            format!("formatTime: invalid format '{}'", fmt).into()
+        ));
+    }
+    Ok(Val::Str(
+        time.format_with_items(items.into_iter()).to_string().into(),
+    ))
+}
+
+/// Normalizes a string for use as label value: it is lowercased, every run of
+/// characters other than letters and digits is replaced by a single `_`, and
+/// leading and trailing `_` are removed. Strings without any letter or digit
+/// are rejected, as they would result in an empty value.
+fn sanitize_label(string: &Val) -> Result<Val> {
+    let string = match string {
+        Val::Str(string) => string,
+        _ => throw!(RuntimeError("sanitizeLabel: expected string".into())),
+    };
+
+    let mut sanitized = String::with_capacity(string.len());
+    for c in string.chars() {
+        if c.is_alphanumeric() {
+            sanitized.extend(c.to_lowercase());
+        } else if !sanitized.is_empty() && !sanitized.ends_with('_') {
+            sanitized.push('_');
+        }
+    }
+    if sanitized.ends_with('_') {
+        sanitized.pop();
+    }
+    if sanitized.is_empty() {
+        throw!(RuntimeError(
+            format!("sanitizeLabel: '{}' has no letters or digits", string).into()
+        ));
+    }
+    Ok(Val::Str(sanitized.into()))
+}
+
+/// Returns the numbers of an array sorted ascending.
+fn sorted_numbers(name: &str, array: &Val) -> Result<Vec<f64>> {
+    let mut numbers = match serde_json::Value::try_from(array)? {
+        serde_json::Value::Array(values) => values
+            .iter()
+            .map(serde_json::Value::as_f64)
+            .collect::<Option<Vec<f64>>>(),
+        _ => None,
+    };
+    match &mut numbers {
+        Some(numbers) => {
+            numbers.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        }
+        None => throw!(RuntimeError(
+            format!("{}: expected array of numbers", name).into()
+        )),
+    }
+    Ok(numbers.unwrap_or_default())
+}
+
+/// Returns `count`, `sum`, `avg`, `min` and `max` of an array of numbers, the
+/// latter three are `null` for an empty array.
+fn stats(array: &Val) -> Result<Val> {
+    let numbers = sorted_numbers("stats", array)?;
+    let sum: f64 = numbers.iter().sum();
+    let avg = match numbers.len() {
+        0 => None,
+        len => Some(sum / len as f64),
+    };
+
+    Ok(Val::from(&serde_json::json!({
+        "count": numbers.len(),
+        "sum": sum,
+        "avg": avg,
+        "min": numbers.first(),
+        "max": numbers.last(),
+    })))
+}
+
+/// Returns the `p`th percentile, `p` in `[0, 100]`, of an array of numbers,
+/// interpolating linearly between the closest ranks. It is `null` for an
+/// empty array.
+fn percentile(array: &Val, p: &Val) -> Result<Val> {
+    let p = match p {
+        Val::Num(p) if (0.0..=100.0).contains(p) => *p,
+        _ => throw!(RuntimeError(
+            "percentile: expected p between 0 and 100".into()
+        )),
+    };
+    let numbers = sorted_numbers("percentile", array)?;
+    if numbers.is_empty() {
+        return Ok(Val::Null);
+    }
+
+    let rank = p / 100.0 * (numbers.len() - 1) as f64;
+    let lower = numbers[rank.floor() as usize];
+    let upper = numbers[rank.ceil() as usize];
+    Ok(Val::Num(lower + (upper - lower) * rank.fract()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num(value: Result<Val>) -> Option<f64> {
+        match value {
+            Ok(Val::Num(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn parse_int_sign() {
+        let parse =
+            |string: &str, radix| num(parse_int(&Val::Str(string.into()), &Val::Num(radix)));
+        assert_eq!(parse("-5", 10.0), Some(-5.0));
+        assert_eq!(parse("-0x1f", 16.0), Some(-31.0));
+        assert_eq!(parse("--5", 10.0), None);
+        assert_eq!(parse("+5", 10.0), None);
+        assert_eq!(parse("-+5", 10.0), None);
+        assert_eq!(parse("0x-1f", 16.0), None);
+    }
+
+    #[test]
+    fn sanitize_label_unicode() {
+        let sanitize = |string: &str| match sanitize_label(&Val::Str(string.into())) {
+            Ok(Val::Str(sanitized)) => Some(sanitized.to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            sanitize("  HP LaserJet (2nd Floor) -- Öffice #3 "),
+            Some("hp_laserjet_2nd_floor_öffice_3".to_string())
+        );
+        assert_eq!(sanitize("Zürich Straße"), Some("zürich_straße".to_string()));
+        assert_eq!(sanitize(" -- # "), None);
+        assert_eq!(sanitize(""), None);
+    }
+}
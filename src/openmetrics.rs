@@ -0,0 +1,334 @@
+use std::fmt::Write;
+
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+
+pub const FORMAT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Returns the name of the gauge family carrying the creation timestamps of a
+/// counter, which is folded into the counter by the OpenMetrics encoder.
+pub fn created_name(counter: &str) -> String {
+    format!(
+        "{}_created",
+        counter.strip_suffix("_total").unwrap_or(counter)
+    )
+}
+
+/// Returns the name of the family carrying the exemplars of a metric. The
+/// family is only rendered by the OpenMetrics encoder, the name can't clash
+/// with valid metric names.
+pub fn exemplar_name(metric: &str) -> String {
+    format!("{}#exemplar", metric)
+}
+
+pub fn is_exemplar_family(name: &str) -> bool {
+    name.ends_with("#exemplar")
+}
+
+/// Prefix of the exemplar labels in an exemplar family, the remaining labels
+/// are the ones of the sample carrying the exemplar.
+pub const EXEMPLAR_LABEL_PREFIX: &str = "#exemplar:";
+
+/// Maximum combined length of the label names and values of an exemplar.
+pub const EXEMPLAR_MAX_LABEL_LENGTH: usize = 128;
+
+fn fmt_float(v: f64) -> String {
+    if v == f64::INFINITY {
+        "+Inf".into()
+    } else if v == f64::NEG_INFINITY {
+        "-Inf".into()
+    } else if v.is_nan() {
+        "NaN".into()
+    } else {
+        format!("{}", v)
+    }
+}
+
+fn escape(v: &str) -> String {
+    v.replace('\\', r"\\")
+        .replace('\n', r"\n")
+        .replace('"', r#"\""#)
+}
+
+fn write_labels(out: &mut String, labels: &[(&str, &str)]) {
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect();
+    write!(out, "{{{}}}", pairs.join(",")).unwrap();
+}
+
+fn write_sample(
+    out: &mut String,
+    name: &str,
+    suffix: &str,
+    labels: &[LabelPair],
+    extra_label: Option<(&str, String)>,
+    value: f64,
+    exemplar: Option<&Metric>,
+) {
+    let mut pairs: Vec<(&str, &str)> = labels
+        .iter()
+        .map(|l| (l.get_name(), l.get_value()))
+        .collect();
+    if let Some((name, value)) = &extra_label {
+        pairs.push((name, value));
+    }
+
+    out.push_str(name);
+    out.push_str(suffix);
+    if !pairs.is_empty() {
+        write_labels(out, &pairs);
+    }
+    write!(out, " {}", fmt_float(value)).unwrap();
+
+    if let Some(exemplar) = exemplar {
+        let exemplar_labels: Vec<(&str, &str)> = exemplar
+            .get_label()
+            .iter()
+            .filter_map(|l| {
+                l.get_name()
+                    .strip_prefix(EXEMPLAR_LABEL_PREFIX)
+                    .map(|name| (name, l.get_value()))
+            })
+            .collect();
+        out.push_str(" # ");
+        write_labels(out, &exemplar_labels);
+        write!(out, " {}", fmt_float(exemplar.get_gauge().get_value())).unwrap();
+        if exemplar.has_timestamp_ms() {
+            write!(out, " {}", exemplar.get_timestamp_ms() as f64 / 1000.0).unwrap();
+        }
+    }
+    out.push('\n');
+}
+
+fn find_metric<'a>(mf: Option<&'a MetricFamily>, labels: &[LabelPair]) -> Option<&'a Metric> {
+    mf?.get_metric().iter().find(|m| m.get_label() == labels)
+}
+
+/// Finds the exemplar of the sample with the given labels.
+fn find_exemplar<'a>(mf: Option<&'a MetricFamily>, labels: &[LabelPair]) -> Option<&'a Metric> {
+    mf?.get_metric().iter().find(|m| {
+        m.get_label()
+            .iter()
+            .filter(|l| !l.get_name().starts_with(EXEMPLAR_LABEL_PREFIX))
+            .eq(labels.iter())
+    })
+}
+
+/// Encodes the metric families in the OpenMetrics text format.
+pub fn encode(metric_families: &[MetricFamily]) -> String {
+    let mut out = String::new();
+
+    let counters: Vec<String> = metric_families
+        .iter()
+        .filter(|mf| mf.get_field_type() == MetricType::COUNTER)
+        .map(|mf| created_name(mf.get_name()))
+        .collect();
+
+    for mf in metric_families {
+        let name = mf.get_name();
+        if counters.iter().any(|c| c == name) || is_exemplar_family(name) {
+            // rendered as part of its counter or sample
+            continue;
+        }
+
+        let (family_name, type_name) = match mf.get_field_type() {
+            MetricType::COUNTER => (name.strip_suffix("_total").unwrap_or(name), "counter"),
+            MetricType::GAUGE => (name, "gauge"),
+            MetricType::HISTOGRAM => (name, "histogram"),
+            MetricType::SUMMARY => (name, "summary"),
+            MetricType::UNTYPED => (name, "unknown"),
+        };
+        writeln!(out, "# TYPE {} {}", family_name, type_name).unwrap();
+        if !mf.get_help().is_empty() {
+            writeln!(out, "# HELP {} {}", family_name, escape(mf.get_help())).unwrap();
+        }
+
+        let created_family = metric_families
+            .iter()
+            .find(|c| c.get_name() == created_name(name));
+        let exemplar_family = metric_families
+            .iter()
+            .find(|e| e.get_name() == exemplar_name(name));
+
+        for m in mf.get_metric() {
+            let labels = m.get_label();
+            let exemplar = find_exemplar(exemplar_family, labels);
+            match mf.get_field_type() {
+                MetricType::COUNTER => {
+                    write_sample(
+                        &mut out,
+                        family_name,
+                        "_total",
+                        labels,
+                        None,
+                        m.get_counter().get_value(),
+                        exemplar,
+                    );
+                    if let Some(created) = find_metric(created_family, labels) {
+                        write_sample(
+                            &mut out,
+                            family_name,
+                            "_created",
+                            labels,
+                            None,
+                            created.get_gauge().get_value(),
+                            None,
+                        );
+                    }
+                }
+                MetricType::GAUGE => write_sample(
+                    &mut out,
+                    name,
+                    "",
+                    labels,
+                    None,
+                    m.get_gauge().get_value(),
+                    None,
+                ),
+                MetricType::UNTYPED => write_sample(
+                    &mut out,
+                    name,
+                    "",
+                    labels,
+                    None,
+                    m.get_untyped().get_value(),
+                    None,
+                ),
+                MetricType::HISTOGRAM => {
+                    let h = m.get_histogram();
+                    // the exemplar belongs to the first bucket containing it
+                    let mut exemplar = exemplar;
+                    let mut inf_seen = false;
+                    for b in h.get_bucket() {
+                        inf_seen |= b.get_upper_bound() == f64::INFINITY;
+                        let bucket_exemplar = match exemplar {
+                            Some(e) if e.get_gauge().get_value() <= b.get_upper_bound() => {
+                                exemplar.take()
+                            }
+                            _ => None,
+                        };
+                        write_sample(
+                            &mut out,
+                            name,
+                            "_bucket",
+                            labels,
+                            Some(("le", fmt_float(b.get_upper_bound()))),
+                            b.get_cumulative_count() as f64,
+                            bucket_exemplar,
+                        );
+                    }
+                    if !inf_seen {
+                        write_sample(
+                            &mut out,
+                            name,
+                            "_bucket",
+                            labels,
+                            Some(("le", fmt_float(f64::INFINITY))),
+                            h.get_sample_count() as f64,
+                            exemplar,
+                        );
+                    }
+                    write_sample(
+                        &mut out,
+                        name,
+                        "_count",
+                        labels,
+                        None,
+                        h.get_sample_count() as f64,
+                        None,
+                    );
+                    write_sample(
+                        &mut out,
+                        name,
+                        "_sum",
+                        labels,
+                        None,
+                        h.get_sample_sum(),
+                        None,
+                    );
+                }
+                MetricType::SUMMARY => {
+                    let s = m.get_summary();
+                    for q in s.get_quantile() {
+                        write_sample(
+                            &mut out,
+                            name,
+                            "",
+                            labels,
+                            Some(("quantile", fmt_float(q.get_quantile()))),
+                            q.get_value(),
+                            None,
+                        );
+                    }
+                    write_sample(
+                        &mut out,
+                        name,
+                        "_count",
+                        labels,
+                        None,
+                        s.get_sample_count() as f64,
+                        None,
+                    );
+                    write_sample(
+                        &mut out,
+                        name,
+                        "_sum",
+                        labels,
+                        None,
+                        s.get_sample_sum(),
+                        None,
+                    );
+                }
+            }
+        }
+    }
+
+    out.push_str("# EOF\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label(name: &str, value: &str) -> LabelPair {
+        let mut label = LabelPair::default();
+        label.set_name(name.to_string());
+        label.set_value(value.to_string());
+        label
+    }
+
+    #[test]
+    fn encode_counter_exemplar() {
+        let mut counter = Metric::default();
+        counter.set_label(vec![label("code", "200")].into());
+        counter.mut_counter().set_value(3.0);
+        let mut requests = MetricFamily::default();
+        requests.set_name("requests_total".to_string());
+        requests.set_field_type(MetricType::COUNTER);
+        requests.mut_metric().push(counter);
+
+        let mut exemplar = Metric::default();
+        exemplar.set_label(
+            vec![
+                label("code", "200"),
+                label(&format!("{}trace_id", EXEMPLAR_LABEL_PREFIX), "abc"),
+            ]
+            .into(),
+        );
+        exemplar.mut_gauge().set_value(1.0);
+        exemplar.set_timestamp_ms(1600000000500);
+        let mut exemplars = MetricFamily::default();
+        exemplars.set_name(exemplar_name("requests_total"));
+        exemplars.set_field_type(MetricType::GAUGE);
+        exemplars.mut_metric().push(exemplar);
+
+        assert_eq!(
+            encode(&[requests, exemplars]),
+            "# TYPE requests counter\n\
+             requests_total{code=\"200\"} 3 # {trace_id=\"abc\"} 1 1600000000.5\n\
+             # EOF\n"
+        );
+    }
+}
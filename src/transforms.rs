@@ -0,0 +1,79 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Transformation of the response body, applied before it is passed to the
+/// module. Fields are addressed by JSON pointer, like `/data/items/0`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transform {
+    /// Base64-decodes the string at the pointer, the decoded string is
+    /// parsed as JSON when `json` is set.
+    DecodeField {
+        pointer: String,
+        #[serde(default)]
+        json: bool,
+    },
+
+    /// Replaces the body with the value at the pointer.
+    PickSubtree { pointer: String },
+
+    /// Renames a key of the object at the pointer, which defaults to the
+    /// body itself.
+    RenameKey {
+        #[serde(default)]
+        pointer: String,
+        from: String,
+        to: String,
+    },
+}
+
+fn field<'a>(body: &'a mut Value, pointer: &str) -> Result<&'a mut Value, String> {
+    body.pointer_mut(pointer)
+        .ok_or_else(|| format!("no field at '{}'", pointer))
+}
+
+impl Transform {
+    fn apply(&self, mut body: Value) -> Result<Value, String> {
+        match self {
+            Transform::DecodeField { pointer, json } => {
+                let value = field(&mut body, pointer)?;
+                let encoded = match value.as_str() {
+                    Some(encoded) => encoded,
+                    None => return Err(format!("field at '{}' is not a string", pointer)),
+                };
+                let decoded = base64::decode(encoded.trim())
+                    .map_err(|e| format!("cannot decode field at '{}': {}", pointer, e))?;
+                *value = if *json {
+                    serde_json::from_slice(&decoded)
+                        .map_err(|e| format!("cannot parse field at '{}': {}", pointer, e))?
+                } else {
+                    Value::String(String::from_utf8_lossy(&decoded).into_owned())
+                };
+                Ok(body)
+            }
+            Transform::PickSubtree { pointer } => Ok(field(&mut body, pointer)?.take()),
+            Transform::RenameKey { pointer, from, to } => {
+                let object = match field(&mut body, pointer)?.as_object_mut() {
+                    Some(object) => object,
+                    None => return Err(format!("field at '{}' is not an object", pointer)),
+                };
+                if let Some(value) = object.remove(from) {
+                    object.insert(to.clone(), value);
+                }
+                Ok(body)
+            }
+        }
+    }
+}
+
+/// Applies the transforms to the body in order.
+pub fn apply(transforms: &[Transform], body: Value) -> Result<Value, String> {
+    transforms
+        .iter()
+        .enumerate()
+        .try_fold(body, |body, (i, transform)| {
+            transform
+                .apply(body)
+                .map_err(|e| format!("transform #{}: {}", i, e))
+        })
+}
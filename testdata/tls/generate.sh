@@ -0,0 +1,27 @@
+#!/bin/sh
+# Generates the certificates served by the TLS tests: a CA and two
+# certificates for 127.0.0.1 signed by it, one valid until 2120 and one
+# expired in 2001. The identities are PKCS #12 files with password `test`.
+set -eu
+cd "$(dirname "$0")"
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+
+openssl req -x509 -newkey rsa:2048 -nodes -subj /CN=jsonnet-exporter-test-ca \
+    -keyout "$tmp/ca.key" -out ca.pem \
+    -not_before 20200101000000Z -not_after 21200101000000Z
+
+identity() {
+    openssl req -newkey rsa:2048 -nodes -subj /CN=localhost \
+        -keyout "$tmp/$1.key" -out "$tmp/$1.csr"
+    printf 'subjectAltName=DNS:localhost,IP:127.0.0.1\n' >"$tmp/ext"
+    openssl x509 -req -in "$tmp/$1.csr" -CA ca.pem -CAkey "$tmp/ca.key" \
+        -set_serial "$4" -extfile "$tmp/ext" -out "$tmp/$1.pem" \
+        -not_before "$2" -not_after "$3"
+    openssl pkcs12 -export -passout pass:test -out "$1.p12" \
+        -inkey "$tmp/$1.key" -in "$tmp/$1.pem" -certfile ca.pem \
+        -keypbe PBE-SHA1-3DES -certpbe PBE-SHA1-3DES -macalg sha1
+}
+
+identity localhost 20200101000000Z 21200101000000Z 1
+identity expired 20000101000000Z 20010101000000Z 2